@@ -0,0 +1,86 @@
+use std::collections::HashSet;
+use crate::ast::{Eval, Node};
+use crate::visitor::{walk, walk_eval, walk_node, Visitor};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    /// A variable that is assigned but never read.
+    UnusedVariable(String),
+    /// A function that is defined but never called or referenced.
+    UnusedFunction(String),
+}
+
+#[derive(Default)]
+struct Usage {
+    assigned: Vec<String>,
+    defined: Vec<String>,
+    referenced: HashSet<String>,
+}
+
+impl Usage {
+    fn assign(&mut self, name: &str) {
+        if !self.assigned.iter().any(|assigned| assigned == name) {
+            self.assigned.push(name.to_string());
+        }
+    }
+}
+
+impl Visitor for Usage {
+    fn visit_node(&mut self, node: &Node) {
+        match node {
+            Node::Assign(name, _) | Node::AssignDefault(name, _) | Node::For(name, _, _) |
+            Node::ForRange(name, _, _, _, _) => {
+                self.assign(name);
+            }
+            Node::DestructureAssign(names, _) => {
+                for name in names {
+                    self.assign(name);
+                }
+            }
+            Node::FnDef(name, _, _) => {
+                self.defined.push(name.clone());
+            }
+            Node::FnCall(name, _) => {
+                self.referenced.insert(name.clone());
+            }
+            Node::SetMember(Eval::String(name), _, _) => {
+                self.referenced.insert(name.clone());
+            }
+            _ => {}
+        }
+        walk_node(self, node);
+    }
+
+    fn visit_eval(&mut self, val: &Eval) {
+        match val {
+            Eval::VarRef(name) | Eval::FnCall(name, _) | Eval::FnRef(name) => {
+                self.referenced.insert(name.clone());
+            }
+            Eval::Assign(name, _) => {
+                self.assign(name);
+            }
+            Eval::GetMember(obj, _) => {
+                if let Eval::String(name) = &**obj {
+                    self.referenced.insert(name.clone());
+                }
+            }
+            _ => {}
+        }
+        walk_eval(self, val);
+    }
+}
+
+/// Reports variables that are assigned but never read and functions that are defined but never used,
+/// in the order they first appear. Calls through a variable count as reading it.
+pub fn find_unused(nodes: &[Node]) -> Vec<Finding> {
+    let mut usage = Usage::default();
+    walk(&mut usage, nodes);
+
+    let variables = usage.assigned.iter()
+        .filter(|name| !usage.referenced.contains(*name))
+        .map(|name| Finding::UnusedVariable(name.clone()));
+    let functions = usage.defined.iter()
+        .filter(|name| !usage.referenced.contains(*name))
+        .map(|name| Finding::UnusedFunction(name.clone()));
+    variables.chain(functions).collect()
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use crate::error::RuntimeError;
 use crate::vm::{Object, Value};
 
 #[derive(Debug, Clone)]
@@ -7,11 +8,21 @@ pub enum Eval {
     Bool(bool),
     Float(f32),
     String(String),
+    Char(char),
+    Null,
+    Unit,
     Array(Vec<Eval>),
     Object(Box<Eval>),
     GetMember(Box<Eval>, String),
     VarRef(String),
     FnCall(String, Vec<Eval>),
+    FnRef(String),
+    /// A `Loop` in expression position, evaluating to the value it breaks with.
+    LoopExpr(Vec<Node>),
+    /// An anonymous function with these parameters, capturing the local scope it is evaluated in.
+    Closure(Vec<String>, Vec<Node>),
+    /// Stores into the current scope like `Node::Assign` and evaluates to the stored value.
+    Assign(String, Box<Eval>),
 
     Add(Box<Eval>, Box<Eval>),
     Sub(Box<Eval>, Box<Eval>),
@@ -28,6 +39,28 @@ pub enum Eval {
     And(Box<Eval>, Box<Eval>),
     Or(Box<Eval>, Box<Eval>),
     Not(Box<Eval>),
+    /// Converts the operand to another type.
+    Cast(Box<Eval>, TypeName),
+}
+
+/// A type `Eval::Cast` can convert to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypeName {
+    Int,
+    Float,
+    String,
+    Bool,
+}
+
+impl TypeName {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TypeName::Int => "int",
+            TypeName::Float => "float",
+            TypeName::String => "string",
+            TypeName::Bool => "bool",
+        }
+    }
 }
 
 impl Eval {
@@ -63,56 +96,66 @@ impl Eval {
     }
     pub fn deref_var_ref(&mut self, map: &mut HashMap<String, Value>) {
         let mut new_val = None;
-        match self {
-            Eval::VarRef(name) => {
-                new_val = Some(map.get(&*name).unwrap().clone().as_eval());
-            },
-            _ => {}
+        if let Eval::VarRef(name) = self {
+            new_val = Some(map.get(&*name).unwrap().clone().as_eval());
         }
 
-        if new_val.is_some(){
-            *self = new_val.unwrap();
+        if let Some(new_val) = new_val {
+            *self = new_val;
         }
     }
     pub fn deref_object_member(&mut self, objects: &mut HashMap<usize, Object>, variables: &mut HashMap<String, Value>) {
-        match self {
-            Eval::GetMember(id_loc, name) => {
-                let id = match &**id_loc {
-                    Eval::Int(id) => *id as usize,
-                    Eval::String(var_name) => {
-                        match variables.get_mut(&var_name.to_string()).unwrap() {
-                            Value::Object(id) => *id as usize,
-                            val => panic!("Expected Object for object id: {:?}", val)
-                        }
+        if let Eval::GetMember(id_loc, name) = self {
+            let id = match &**id_loc {
+                Eval::Int(id) => *id as usize,
+                Eval::String(var_name) => {
+                    match variables.get_mut(&var_name.to_string()).unwrap() {
+                        Value::Object(id) => *id,
+                        val => panic!("Expected Object for object id: {:?}", val)
                     }
-                    _ => panic!("Expected int for object id")
-                };
+                }
+                _ => panic!("Expected int for object id")
+            };
 
-                let obj = objects.get_mut(&id).unwrap();
-                *self = obj.fields.get_mut(name).unwrap().as_eval();
-            }
-            _ => {}
+            let obj = objects.get_mut(&id).unwrap();
+            *self = obj.fields.get_mut(name).unwrap().as_eval();
         }
     }
     pub fn is_an_operator(&self) -> bool {
-        match self {
-            Eval::Add(_, _) => true,
-            Eval::Sub(_, _) => true,
-            Eval::Mul(_, _) => true,
-            Eval::Div(_, _) => true,
-            Eval::Mod(_, _) => true,
-            Eval::Pow(_, _) => true,
-            Eval::Eq(_, _) => true,
-            Eval::Ne(_, _) => true,
-            Eval::Gt(_, _) => true,
-            Eval::Ge(_, _) => true,
-            Eval::Lt(_, _) => true,
-            Eval::Le(_, _) => true,
-            Eval::And(_, _) => true,
-            Eval::Or(_, _) => true,
-            Eval::Not(_) => true,
-            _ => false
+        matches!(
+            self,
+            Eval::Add(_, _) | Eval::Sub(_, _) | Eval::Mul(_, _) | Eval::Div(_, _) |
+            Eval::Mod(_, _) | Eval::Pow(_, _) | Eval::Eq(_, _) | Eval::Ne(_, _) |
+            Eval::Gt(_, _) | Eval::Ge(_, _) | Eval::Lt(_, _) | Eval::Le(_, _) |
+            Eval::And(_, _) | Eval::Or(_, _) | Eval::Not(_) | Eval::Cast(_, _)
+        )
+    }
+
+    /// Parses hex digits such as `"ff"` or `"-0x1F"` into an `Eval::Int`.
+    pub fn int_from_hex(digits: &str) -> Result<Eval, RuntimeError> {
+        Eval::int_from_radix(digits, "0x", 16)
+    }
+
+    /// Parses binary digits such as `"1010"` or `"0b11"` into an `Eval::Int`.
+    pub fn int_from_bin(digits: &str) -> Result<Eval, RuntimeError> {
+        Eval::int_from_radix(digits, "0b", 2)
+    }
+
+    /// An optional `-`, then the optional prefix, then nothing but digits of the radix.
+    fn int_from_radix(digits: &str, prefix: &str, radix: u32) -> Result<Eval, RuntimeError> {
+        let invalid = || RuntimeError::InvalidLiteral { literal: digits.to_string(), radix };
+        let (sign, unsigned) = match digits.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", digits)
+        };
+        let unsigned = unsigned.strip_prefix(prefix).unwrap_or(unsigned);
+        // `from_str_radix` takes a sign of its own, so check the digits before handing them over.
+        if unsigned.is_empty() || !unsigned.chars().all(|c| c.is_digit(radix)) {
+            return Err(invalid());
         }
+        i32::from_str_radix(&format!("{}{}", sign, unsigned), radix)
+            .map(Eval::Int)
+            .map_err(|_| invalid())
     }
 }
 
@@ -120,20 +163,65 @@ impl Eval {
 #[derive(Debug, Clone)]
 pub enum Node {
     Assign(String, Eval),
+    /// Assigns only if the variable is not yet set in the active scope.
+    AssignDefault(String, Eval),
+    DestructureAssign(Vec<String>, Eval),
     Unassign(String),
     SetMember(Eval, String, Eval),
     CreateObject(Eval, Vec<(String, Eval)>),
     DeleteObject(Eval),
-    Conditional(Vec<(Eval, Vec<Node>)>, Vec<Node>),
+    /// An if / else-if chain running the body of the first true condition, then the optional else block.
+    Conditional(Vec<(Eval, Vec<Node>)>, Option<Vec<Node>>),
+    /// Runs the block when the condition is not true, usually to `return` or `break` early.
+    Guard(Eval, Vec<Node>),
+    Switch(Eval, Vec<(Vec<Eval>, Vec<Node>)>, Vec<Node>),
 
     Loop(Vec<Node>),
     WhileLoop(Eval, Vec<Node>),
     For(String, Eval, Vec<Node>),
+    /// Counts the variable from start up to, but not including, stop by step, which may be negative.
+    ForRange(String, Eval, Eval, Eval, Vec<Node>),
+    /// Runs the body the given number of times.
+    Times(Eval, Vec<Node>),
+    /// Runs the body, then stops once the condition is true. The body always runs at least once.
+    RepeatUntil(Vec<Node>, Eval),
     Break,
+    /// Breaks out of the enclosing loop, handing it a value.
+    BreakWith(Eval),
     Continue,
 
     FnDef(String, Vec<String>, Vec<Node>),
     Return(Eval),
 
     FnCall(String, Vec<Eval>),
+}
+
+impl Node {
+    /// The variant name, for diagnostics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Node::Assign(_, _) => "Assign",
+            Node::AssignDefault(_, _) => "AssignDefault",
+            Node::DestructureAssign(_, _) => "DestructureAssign",
+            Node::Unassign(_) => "Unassign",
+            Node::SetMember(_, _, _) => "SetMember",
+            Node::CreateObject(_, _) => "CreateObject",
+            Node::DeleteObject(_) => "DeleteObject",
+            Node::Conditional(_, _) => "Conditional",
+            Node::Guard(_, _) => "Guard",
+            Node::Switch(_, _, _) => "Switch",
+            Node::Loop(_) => "Loop",
+            Node::WhileLoop(_, _) => "WhileLoop",
+            Node::For(_, _, _) => "For",
+            Node::ForRange(_, _, _, _, _) => "ForRange",
+            Node::RepeatUntil(_, _) => "RepeatUntil",
+            Node::Times(_, _) => "Times",
+            Node::Break => "Break",
+            Node::BreakWith(_) => "BreakWith",
+            Node::Continue => "Continue",
+            Node::FnDef(_, _, _) => "FnDef",
+            Node::Return(_) => "Return",
+            Node::FnCall(_, _) => "FnCall",
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,184 @@
+use std::fmt::{Display, Formatter};
+use crate::vm::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    ArgumentType {
+        function: String,
+        index: usize,
+        expected: &'static str,
+        found: &'static str,
+    },
+    ParameterType {
+        function: String,
+        parameter: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+    InvalidArgument {
+        function: String,
+        message: String,
+    },
+    TooManyArguments {
+        function: String,
+        max: usize,
+        found: usize,
+    },
+    NegativeCount(i32),
+    CountType(&'static str),
+    InvalidCodePoint(i64),
+    DestructureType(&'static str),
+    InvalidCast {
+        from: &'static str,
+        to: &'static str,
+    },
+    InvalidLiteral {
+        literal: String,
+        radix: u32,
+    },
+    Unserializable(&'static str),
+    CyclicObject(usize),
+    FrozenObject(usize),
+    UnknownField {
+        object: usize,
+        field: String,
+    },
+    InvalidJson(String),
+    NotIterable(&'static str),
+    RangeBound(&'static str),
+    ZeroStep,
+    Unhashable(&'static str),
+    LoopLimit(usize),
+    ExpressionTooDeep(usize),
+    Timeout,
+    Sandboxed(&'static str),
+    MissingMain,
+    NoProgram,
+    FunctionUnavailable(String),
+    AssertionFailed(String),
+    Io(String),
+    DestructureLength {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl RuntimeError {
+    pub fn argument_type(function: &str, index: usize, expected: &'static str, found: &Value) -> Self {
+        RuntimeError::ArgumentType {
+            function: function.to_string(),
+            index,
+            expected,
+            found: found.type_name(),
+        }
+    }
+
+    pub fn invalid_argument(function: &str, message: &str) -> Self {
+        RuntimeError::InvalidArgument {
+            function: function.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::ArgumentType { function, index, expected, found } => {
+                write!(f, "Function {} expected {} for argument {}, found {}", function, expected, index + 1, found)
+            }
+            RuntimeError::ParameterType { function, parameter, expected, found } => {
+                write!(f, "Function {} expected {} for parameter {}, found {}", function, expected, parameter, found)
+            }
+            RuntimeError::InvalidArgument { function, message } => {
+                write!(f, "Invalid argument to {}: {}", function, message)
+            }
+            RuntimeError::TooManyArguments { function, max, found } => {
+                write!(f, "Function {} takes at most {} arguments, {} given", function, max, found)
+            }
+            RuntimeError::CountType(found) => {
+                write!(f, "Repeat count must be an int, found {}", found)
+            }
+            RuntimeError::NegativeCount(count) => {
+                write!(f, "Count must not be negative, got {}", count)
+            }
+            RuntimeError::InvalidCodePoint(code) => {
+                write!(f, "{} is not a valid character code point", code)
+            }
+            RuntimeError::Unserializable(found) => {
+                write!(f, "Cannot serialize {} as JSON", found)
+            }
+            RuntimeError::CyclicObject(id) => {
+                write!(f, "Object {} contains a reference to itself", id)
+            }
+            RuntimeError::FrozenObject(id) => {
+                write!(f, "Object {} is frozen and cannot be modified", id)
+            }
+            RuntimeError::InvalidJson(message) => {
+                write!(f, "Invalid JSON: {}", message)
+            }
+            RuntimeError::NotIterable(found) => {
+                write!(f, "Cannot iterate over {}", found)
+            }
+            RuntimeError::UnknownField { object, field } => {
+                write!(f, "Object {} has no field {}", object, field)
+            }
+            RuntimeError::RangeBound(found) => {
+                write!(f, "Range bounds and step must be ints, found {}", found)
+            }
+            RuntimeError::ZeroStep => {
+                write!(f, "Range step cannot be zero")
+            }
+            RuntimeError::Unhashable(found) => {
+                write!(f, "Cannot use {} as a key", found)
+            }
+            RuntimeError::LoopLimit(max) => {
+                write!(f, "Loop exceeded the limit of {} iterations", max)
+            }
+            RuntimeError::ExpressionTooDeep(max) => {
+                write!(f, "Expression nested deeper than {} levels", max)
+            }
+            RuntimeError::Timeout => {
+                write!(f, "Execution timed out")
+            }
+            RuntimeError::Sandboxed(function) => {
+                write!(f, "Function {} is not available in a sandboxed VM", function)
+            }
+            RuntimeError::MissingMain => {
+                write!(f, "Program does not define a main function")
+            }
+            RuntimeError::NoProgram => {
+                write!(f, "No program is loaded")
+            }
+            RuntimeError::FunctionUnavailable(name) => {
+                write!(f, "Function {} does not exist or is already running", name)
+            }
+            RuntimeError::AssertionFailed(message) => {
+                write!(f, "Assertion failed: {}", message)
+            }
+            RuntimeError::Io(message) => {
+                write!(f, "IO error: {}", message)
+            }
+            RuntimeError::InvalidCast { from, to } => {
+                write!(f, "Cannot cast {} to {}", from, to)
+            }
+            RuntimeError::InvalidLiteral { literal, radix } => {
+                write!(f, "Invalid base {} int literal {:?}", radix, literal)
+            }
+            RuntimeError::DestructureType(found) => {
+                write!(f, "Only arrays can be destructured, found {}", found)
+            }
+            RuntimeError::DestructureLength { expected, found } => {
+                write!(f, "Cannot destructure {} values into {} names", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+impl From<std::io::Error> for RuntimeError {
+    fn from(err: std::io::Error) -> Self {
+        RuntimeError::Io(err.to_string())
+    }
+}
@@ -0,0 +1,316 @@
+use std::fmt::Write;
+use crate::ast::Eval;
+use crate::error::RuntimeError;
+use crate::vm::{format_float, Value, VirtualMachine};
+
+fn write_json_string(out: &mut String, val: &str) {
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => { out.push_str("\\\"") }
+            '\\' => { out.push_str("\\\\") }
+            '\n' => { out.push_str("\\n") }
+            '\r' => { out.push_str("\\r") }
+            '\t' => { out.push_str("\\t") }
+            c if (c as u32) < 0x20 => { write!(out, "\\u{:04x}", c as u32).unwrap() }
+            c => { out.push(c) }
+        }
+    }
+    out.push('"');
+}
+
+fn write_json(vm: &VirtualMachine, val: &Value, visiting: &mut Vec<usize>, out: &mut String) -> Result<(), RuntimeError> {
+    match val {
+        Value::Int(val) => { write!(out, "{}", val).unwrap() }
+        Value::Bool(val) => { write!(out, "{}", val).unwrap() }
+        Value::Float(val) => {
+            if !val.is_finite() {
+                return Err(RuntimeError::Unserializable("non-finite float"));
+            }
+            out.push_str(&format_float(*val))
+        }
+        Value::String(val) => { write_json_string(out, val) }
+        Value::Char(val) => { write_json_string(out, &val.to_string()) }
+        Value::Null => { out.push_str("null") }
+        Value::Array(items) => {
+            out.push('[');
+            for (index, item) in items.iter().enumerate() {
+                if index != 0 {
+                    out.push(',');
+                }
+                write_json(vm, item, visiting, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(id) => {
+            if visiting.contains(id) {
+                return Err(RuntimeError::CyclicObject(*id));
+            }
+            visiting.push(*id);
+
+            let obj = vm.objects.get(id).unwrap();
+            let mut names: Vec<&String> = obj.fields.keys().collect();
+            names.sort();
+
+            out.push('{');
+            for (index, name) in names.into_iter().enumerate() {
+                if index != 0 {
+                    out.push(',');
+                }
+                write_json_string(out, name);
+                out.push(':');
+                write_json(vm, &obj.fields[name], visiting, out)?;
+            }
+            out.push('}');
+
+            visiting.pop();
+        }
+        Value::Function(_) | Value::Unit => {
+            return Err(RuntimeError::Unserializable(val.type_name()));
+        }
+    }
+    Ok(())
+}
+
+/// Serializes a value as JSON, objects are dumped field by field with their keys sorted.
+pub fn to_json(vm: &VirtualMachine, val: &Value) -> Result<String, RuntimeError> {
+    let mut out = String::new();
+    write_json(vm, val, &mut vec![], &mut out)?;
+    Ok(out)
+}
+
+pub fn builtin_to_json(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    Ok(Some(Value::String(to_json(vm, &val)?)))
+}
+
+/// A parsed document, only turned into values once all of it parsed so a malformed one
+/// leaves no objects behind.
+enum Json {
+    Scalar(Value),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn into_value(self, vm: &mut VirtualMachine) -> Value {
+        match self {
+            Json::Scalar(val) => val,
+            Json::Array(items) => Value::Array(items.into_iter().map(|item| item.into_value(vm)).collect()),
+            Json::Object(fields) => {
+                let fields = fields.into_iter().map(|(name, val)| (name, val.into_value(vm))).collect();
+                Value::Object(vm.allocate_object(fields))
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> RuntimeError {
+        RuntimeError::InvalidJson(format!("{} at position {}", message, self.pos))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\n' | '\r' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), RuntimeError> {
+        self.skip_whitespace();
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected '{}'", expected)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn expect_word(&mut self, word: &str, val: Json) -> Result<Json, RuntimeError> {
+        for expected in word.chars() {
+            if self.peek() != Some(expected) {
+                return Err(self.error(&format!("Expected '{}'", word)));
+            }
+            self.pos += 1;
+        }
+        Ok(val)
+    }
+
+    fn parse_value(&mut self) -> Result<Json, RuntimeError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => Ok(Json::Scalar(Value::String(self.parse_string()?))),
+            Some('t') => self.expect_word("true", Json::Scalar(Value::Bool(true))),
+            Some('f') => self.expect_word("false", Json::Scalar(Value::Bool(false))),
+            Some('n') => self.expect_word("null", Json::Scalar(Value::Null)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(c) => Err(self.error(&format!("Unexpected character '{}'", c))),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+
+    /// Consumes a run of digits, failing if there is not at least one.
+    fn expect_digits(&mut self) -> Result<(), RuntimeError> {
+        if !matches!(self.peek(), Some('0'..='9')) {
+            return Err(self.error("Expected a digit"));
+        }
+        while matches!(self.peek(), Some('0'..='9')) {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Reads `-? (0 | [1-9][0-9]*) (. [0-9]+)? ([eE] [+-]? [0-9]+)?`, an int unless it has a
+    /// fraction or an exponent.
+    fn parse_number(&mut self) -> Result<Json, RuntimeError> {
+        let start = self.pos;
+        let mut is_float = false;
+        if self.peek() == Some('-') {
+            self.pos += 1;
+        }
+        if self.peek() == Some('0') {
+            self.pos += 1;
+            if matches!(self.peek(), Some('0'..='9')) {
+                return Err(self.error("Leading zeros are not allowed"));
+            }
+        } else {
+            self.expect_digits()?;
+        }
+        if self.peek() == Some('.') {
+            self.pos += 1;
+            is_float = true;
+            self.expect_digits()?;
+        }
+        if matches!(self.peek(), Some('e' | 'E')) {
+            self.pos += 1;
+            is_float = true;
+            if matches!(self.peek(), Some('+' | '-')) {
+                self.pos += 1;
+            }
+            self.expect_digits()?;
+        }
+
+        let text: String = self.chars[start..self.pos].iter().collect();
+        let res = if is_float {
+            text.parse().map(Value::Float).ok()
+        } else {
+            text.parse().map(Value::Int).ok()
+        };
+        res.map(Json::Scalar).ok_or_else(|| RuntimeError::InvalidJson(format!("Invalid number {} at position {}", text, start)))
+    }
+
+    fn parse_hex_escape(&mut self) -> Result<u32, RuntimeError> {
+        let digits: String = self.chars.iter().skip(self.pos).take(4).collect();
+        let code = u32::from_str_radix(&digits, 16).map_err(|_| self.error("Invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn parse_string(&mut self) -> Result<String, RuntimeError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let c = self.peek().ok_or_else(|| self.error("Unterminated string"))?;
+            self.pos += 1;
+            match c {
+                '"' => { return Ok(out); }
+                '\\' => {
+                    let escaped = self.peek().ok_or_else(|| self.error("Unterminated string"))?;
+                    self.pos += 1;
+                    match escaped {
+                        '"' | '\\' | '/' => { out.push(escaped) }
+                        'n' => { out.push('\n') }
+                        'r' => { out.push('\r') }
+                        't' => { out.push('\t') }
+                        'b' => { out.push('\u{8}') }
+                        'f' => { out.push('\u{c}') }
+                        'u' => {
+                            let mut code = self.parse_hex_escape()?;
+                            if (0xd800..0xdc00).contains(&code) {
+                                self.expect_word("\\u", Json::Scalar(Value::Null))?;
+                                let low = self.parse_hex_escape()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("Invalid unicode escape"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            out.push(char::from_u32(code).ok_or_else(|| self.error("Invalid unicode escape"))?);
+                        }
+                        _ => { return Err(self.error("Invalid escape")); }
+                    }
+                }
+                c => { out.push(c) }
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Json, RuntimeError> {
+        self.expect('[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => { self.pos += 1; }
+                Some(']') => { self.pos += 1; return Ok(Json::Array(items)); }
+                _ => { return Err(self.error("Expected ',' or ']'")); }
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, RuntimeError> {
+        self.expect('{')?;
+        let mut fields = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+        } else {
+            loop {
+                self.skip_whitespace();
+                let name = self.parse_string()?;
+                self.expect(':')?;
+                fields.push((name, self.parse_value()?));
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(',') => { self.pos += 1; }
+                    Some('}') => { self.pos += 1; break; }
+                    _ => { return Err(self.error("Expected ',' or '}'")); }
+                }
+            }
+        }
+
+        Ok(Json::Object(fields))
+    }
+}
+
+/// Parses JSON into values, every JSON object is allocated as a new VM object.
+pub fn from_json(vm: &mut VirtualMachine, json: &str) -> Result<Value, RuntimeError> {
+    let mut parser = Parser { chars: json.chars().collect(), pos: 0 };
+    let val = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.chars.len() {
+        return Err(parser.error("Unexpected trailing characters"));
+    }
+    Ok(val.into_value(vm))
+}
+
+pub fn builtin_from_json(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let json = vm.expect_string(args[0].clone(), "from_json", 0)?;
+    Ok(Some(from_json(vm, &json)?))
+}
@@ -1,2 +1,7 @@
+pub mod analysis;
 pub mod ast;
+pub mod error;
+pub mod json;
+pub mod render;
+pub mod visitor;
 pub mod vm;
@@ -0,0 +1,140 @@
+use crate::ast::{Eval, Node};
+use crate::vm::format_float;
+
+fn binary_operator(val: &Eval) -> Option<(&Eval, &'static str, &Eval)> {
+    let (lhs, op, rhs) = match val {
+        Eval::Add(lhs, rhs) => (lhs, "+", rhs),
+        Eval::Sub(lhs, rhs) => (lhs, "-", rhs),
+        Eval::Mul(lhs, rhs) => (lhs, "*", rhs),
+        Eval::Div(lhs, rhs) => (lhs, "/", rhs),
+        Eval::Mod(lhs, rhs) => (lhs, "%", rhs),
+        Eval::Pow(lhs, rhs) => (lhs, "**", rhs),
+        Eval::Eq(lhs, rhs) => (lhs, "==", rhs),
+        Eval::Ne(lhs, rhs) => (lhs, "!=", rhs),
+        Eval::Gt(lhs, rhs) => (lhs, ">", rhs),
+        Eval::Ge(lhs, rhs) => (lhs, ">=", rhs),
+        Eval::Lt(lhs, rhs) => (lhs, "<", rhs),
+        Eval::Le(lhs, rhs) => (lhs, "<=", rhs),
+        Eval::And(lhs, rhs) => (lhs, "&&", rhs),
+        Eval::Or(lhs, rhs) => (lhs, "||", rhs),
+        _ => return None
+    };
+    Some((lhs, op, rhs))
+}
+
+/// Renders an operand, parenthesizing nested operators so precedence never has to be guessed.
+fn render_operand(val: &Eval) -> String {
+    if val.is_an_operator() {
+        format!("({})", render_eval(val))
+    } else {
+        render_eval(val)
+    }
+}
+
+/// Renders an object location, `#id` for literal ids and the variable name otherwise.
+fn render_object_ref(val: &Eval) -> String {
+    match val {
+        Eval::Int(id) => format!("#{}", id),
+        Eval::String(name) => name.clone(),
+        _ => render_eval(val)
+    }
+}
+
+fn render_list(vals: &[Eval]) -> String {
+    vals.iter().map(render_eval).collect::<Vec<_>>().join(", ")
+}
+
+fn render_block(nodes: &[Node]) -> String {
+    if nodes.is_empty() {
+        "{}".to_string()
+    } else {
+        format!("{{ {} }}", render(nodes))
+    }
+}
+
+/// Renders an expression as source-like text.
+pub fn render_eval(val: &Eval) -> String {
+    if let Some((lhs, op, rhs)) = binary_operator(val) {
+        return format!("{} {} {}", render_operand(lhs), op, render_operand(rhs));
+    }
+
+    match val {
+        Eval::Int(val) => val.to_string(),
+        Eval::Bool(val) => val.to_string(),
+        Eval::Float(val) => format_float(*val),
+        Eval::String(val) => format!("{:?}", val),
+        Eval::Char(val) => format!("{:?}", val),
+        Eval::Null => "null".to_string(),
+        Eval::Unit => "()".to_string(),
+        Eval::Array(items) => format!("[{}]", render_list(items)),
+        Eval::Object(id) => render_object_ref(id),
+        Eval::GetMember(obj, member) => format!("{}.{}", render_object_ref(obj), member),
+        Eval::VarRef(name) => name.clone(),
+        Eval::FnCall(name, args) => format!("{}({})", name, render_list(args)),
+        Eval::FnRef(name) => format!("&{}", name),
+        Eval::LoopExpr(body) => format!("loop {}", render_block(body)),
+        Eval::Closure(args, body) => format!("fn({}) {}", args.join(", "), render_block(body)),
+        Eval::Assign(name, val) => format!("({} = {})", name, render_eval(val)),
+        Eval::Not(val) => format!("!{}", render_operand(val)),
+        Eval::Cast(val, to) => format!("{} as {}", render_operand(val), to.name()),
+        _ => unreachable!("binary operators are rendered above")
+    }
+}
+
+fn render_node(node: &Node) -> String {
+    match node {
+        Node::Assign(name, val) => format!("{} = {};", name, render_eval(val)),
+        Node::AssignDefault(name, val) => format!("{} ??= {};", name, render_eval(val)),
+        Node::DestructureAssign(names, val) => format!("[{}] = {};", names.join(", "), render_eval(val)),
+        Node::Unassign(name) => format!("unset {};", name),
+        Node::SetMember(obj, member, val) => {
+            format!("{}.{} = {};", render_object_ref(obj), member, render_eval(val))
+        }
+        Node::CreateObject(obj, fields) => {
+            let fields: Vec<String> = fields.iter()
+                .map(|(name, val)| format!("{}: {}", name, render_eval(val)))
+                .collect();
+            format!("{} = {{ {} }};", render_object_ref(obj), fields.join(", "))
+        }
+        Node::DeleteObject(obj) => format!("delete {};", render_object_ref(obj)),
+        Node::Conditional(conditions, else_block) => {
+            let branches: Vec<String> = conditions.iter()
+                .map(|(condition, body)| format!("if ({}) {}", render_eval(condition), render_block(body)))
+                .collect();
+            let mut out = branches.join(" else ");
+            if let Some(else_block) = else_block {
+                out.push_str(&format!(" else {}", render_block(else_block)));
+            }
+            out
+        }
+        Node::Guard(condition, body) => format!("guard ({}) else {}", render_eval(condition), render_block(body)),
+        Node::Switch(subject, cases, default) => {
+            let mut parts: Vec<String> = cases.iter()
+                .map(|(keys, body)| format!("case {}: {}", render_list(keys), render_block(body)))
+                .collect();
+            if !default.is_empty() {
+                parts.push(format!("default: {}", render_block(default)));
+            }
+            format!("switch ({}) {{ {} }}", render_eval(subject), parts.join(" "))
+        }
+        Node::Loop(body) => format!("loop {}", render_block(body)),
+        Node::WhileLoop(condition, body) => format!("while ({}) {}", render_eval(condition), render_block(body)),
+        Node::For(name, iterable, body) => format!("for ({} in {}) {}", name, render_eval(iterable), render_block(body)),
+        Node::ForRange(name, start, stop, step, body) => {
+            format!("for ({} in {}..{} by {}) {}", name, render_eval(start), render_eval(stop), render_eval(step), render_block(body))
+        }
+        Node::Times(count, body) => format!("times ({}) {}", render_eval(count), render_block(body)),
+        Node::RepeatUntil(body, condition) => format!("repeat {} until ({});", render_block(body), render_eval(condition)),
+        Node::Break => "break;".to_string(),
+        Node::BreakWith(val) => format!("break {};", render_eval(val)),
+        Node::Continue => "continue;".to_string(),
+        Node::FnDef(name, args, body) => format!("fn {}({}) {}", name, args.join(", "), render_block(body)),
+        Node::Return(val) => format!("return {};", render_eval(val)),
+        Node::FnCall(name, args) => format!("{}({});", name, render_list(args)),
+    }
+}
+
+/// Renders statements as source-like text on a single line, e.g. `while (i < 10) { print(i); }`.
+pub fn render(nodes: &[Node]) -> String {
+    nodes.iter().map(render_node).collect::<Vec<_>>().join(" ")
+}
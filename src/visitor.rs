@@ -0,0 +1,112 @@
+use crate::ast::{Eval, Node};
+
+/// Walks the AST. Every method recurses into children by default, so an analysis pass only
+/// overrides the methods it cares about and calls the matching `walk_*` to keep descending.
+pub trait Visitor {
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node);
+    }
+
+    fn visit_eval(&mut self, val: &Eval) {
+        walk_eval(self, val);
+    }
+}
+
+/// Visits every statement in order.
+pub fn walk<V: Visitor + ?Sized>(visitor: &mut V, nodes: &[Node]) {
+    for node in nodes {
+        visitor.visit_node(node);
+    }
+}
+
+pub fn walk_node<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) {
+    match node {
+        Node::Assign(_, val) | Node::AssignDefault(_, val) | Node::DestructureAssign(_, val) => {
+            visitor.visit_eval(val);
+        }
+        Node::Unassign(_) | Node::Break | Node::Continue => {}
+        Node::SetMember(obj, _, val) => {
+            visitor.visit_eval(obj);
+            visitor.visit_eval(val);
+        }
+        Node::CreateObject(obj, fields) => {
+            visitor.visit_eval(obj);
+            for (_, val) in fields {
+                visitor.visit_eval(val);
+            }
+        }
+        Node::DeleteObject(obj) => {
+            visitor.visit_eval(obj);
+        }
+        Node::Conditional(conditions, else_block) => {
+            for (condition, body) in conditions {
+                visitor.visit_eval(condition);
+                walk(visitor, body);
+            }
+            if let Some(else_block) = else_block {
+                walk(visitor, else_block);
+            }
+        }
+        Node::Switch(subject, cases, default) => {
+            visitor.visit_eval(subject);
+            for (keys, body) in cases {
+                for key in keys {
+                    visitor.visit_eval(key);
+                }
+                walk(visitor, body);
+            }
+            walk(visitor, default);
+        }
+        Node::Loop(body) | Node::FnDef(_, _, body) => {
+            walk(visitor, body);
+        }
+        Node::WhileLoop(condition, body) | Node::For(_, condition, body) | Node::Times(condition, body) |
+        Node::Guard(condition, body) => {
+            visitor.visit_eval(condition);
+            walk(visitor, body);
+        }
+        Node::ForRange(_, start, stop, step, body) => {
+            visitor.visit_eval(start);
+            visitor.visit_eval(stop);
+            visitor.visit_eval(step);
+            walk(visitor, body);
+        }
+        Node::RepeatUntil(body, condition) => {
+            walk(visitor, body);
+            visitor.visit_eval(condition);
+        }
+        Node::Return(val) | Node::BreakWith(val) => {
+            visitor.visit_eval(val);
+        }
+        Node::FnCall(_, args) => {
+            for arg in args {
+                visitor.visit_eval(arg);
+            }
+        }
+    }
+}
+
+pub fn walk_eval<V: Visitor + ?Sized>(visitor: &mut V, val: &Eval) {
+    match val {
+        Eval::Int(_) | Eval::Bool(_) | Eval::Float(_) | Eval::String(_) | Eval::Char(_) |
+        Eval::Null | Eval::Unit | Eval::VarRef(_) | Eval::FnRef(_) => {}
+        Eval::Array(items) | Eval::FnCall(_, items) => {
+            for item in items {
+                visitor.visit_eval(item);
+            }
+        }
+        Eval::Object(val) | Eval::GetMember(val, _) | Eval::Not(val) | Eval::Assign(_, val) | Eval::Cast(val, _) => {
+            visitor.visit_eval(val);
+        }
+        Eval::LoopExpr(body) | Eval::Closure(_, body) => {
+            walk(visitor, body);
+        }
+        Eval::Add(lhs, rhs) | Eval::Sub(lhs, rhs) | Eval::Mul(lhs, rhs) | Eval::Div(lhs, rhs) |
+        Eval::Mod(lhs, rhs) | Eval::Pow(lhs, rhs) | Eval::Eq(lhs, rhs) | Eval::Ne(lhs, rhs) |
+        Eval::Gt(lhs, rhs) | Eval::Ge(lhs, rhs) | Eval::Lt(lhs, rhs) | Eval::Le(lhs, rhs) |
+        Eval::And(lhs, rhs) | Eval::Or(lhs, rhs) => {
+            visitor.visit_eval(lhs);
+            visitor.visit_eval(rhs);
+        }
+    }
+}
@@ -1,12 +1,20 @@
-use std::collections::{HashMap};
-use std::fmt::{Debug, Formatter};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp::Ordering;
+use std::cell::RefCell;
+use std::fmt::{Debug, Display, Formatter};
 use std::iter::IntoIterator;
 use std::string::ToString;
 use std::io::Write;
-use crate::ast::{Eval, Node};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::ast::{Eval, Node, TypeName};
+use crate::error::RuntimeError;
+use crate::json::{builtin_from_json, builtin_to_json};
 
 
 const VARIADIC_ARG_NAME: &str = "varargs";
+pub const DEFAULT_LOOP_COUNTER_NAME: &str = "__iter";
+/// Generated functions allowed to pile up before `collect_functions` first looks for unused ones.
+const MIN_FUNCTION_COLLECTION: usize = 64;
 
 #[derive(Debug ,Clone, PartialEq)]
 pub enum Value {
@@ -14,24 +22,69 @@ pub enum Value {
     Bool(bool),
     Float(f32),
     String(String),
+    Char(char),
     Array(Vec<Value>),
     Object(usize),
+    Function(String),
+    Null,
+    /// The result of calling a function that returns nothing.
+    Unit,
 }
 
 
 pub trait Callable: Debug {
-    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Option<Value>;
+    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError>;
     fn args_len(&self) -> usize;
     fn minimum_args_len(&self) -> usize;
     fn is_variadic(&self) -> bool;
+    /// Upper bound on the arguments a variadic callable accepts, `None` means unbounded.
+    fn max_args_len(&self) -> Option<usize> { None }
+    /// Values the callable holds on to, so the generated functions they name are not collected.
+    fn held_values(&self) -> Vec<Value> { vec![] }
 }
 
+/// A type hint for a parameter of a `DefinedFunction`, checked before the arguments are bound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgType {
+    Int,
+    Float,
+    String,
+    Bool,
+    Any,
+}
+
+impl ArgType {
+    pub fn accepts(&self, val: &Value) -> bool {
+        matches!(
+            (self, val),
+            (ArgType::Any, _) | (ArgType::Int, Value::Int(_)) | (ArgType::Float, Value::Float(_)) |
+            (ArgType::String, Value::String(_)) | (ArgType::Bool, Value::Bool(_))
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArgType::Int => "int",
+            ArgType::Float => "float",
+            ArgType::String => "string",
+            ArgType::Bool => "bool",
+            ArgType::Any => "any",
+        }
+    }
+}
+
+/// A function written in script code.
+///
+/// Arguments are passed by value, except objects: a `Value::Object` is only an id, so the
+/// function sees the caller's object and any `SetMember` on it is visible to the caller.
+/// Wrap an argument in `pass_by_value(...)` to hand the function its own copy instead.
 #[derive(Debug)]
 pub struct DefinedFunction {
     name: String,
     args: Vec<String>,
     body: Vec<Node>,
     has_variadic: bool,
+    arg_types: Vec<ArgType>,
 }
 
 impl DefinedFunction {
@@ -40,50 +93,85 @@ impl DefinedFunction {
             name,
             args,
             body,
-            has_variadic
+            has_variadic,
+            arg_types: vec![],
         }
     }
-}
 
-impl Callable for DefinedFunction {
-    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Option<Value> {
-        println!("Calling function: {}", self.name);
-        if vm.local.is_some() {
-            vm.locals.push(vm.local.take().unwrap());
-        }
+    /// Gives the named parameters type hints in order. Parameters without one accept any value.
+    pub fn with_arg_types(mut self, arg_types: Vec<ArgType>) -> Self {
+        self.arg_types = arg_types;
+        self
+    }
+}
 
-        vm.local = Some(HashMap::new());
+impl DefinedFunction {
+    /// Binds the arguments into the new scope. Like any variable, each takes a use of the objects it
+    /// holds, given back when the scope is dropped.
+    fn bind(&self, vm: &mut VirtualMachine, args: Vec<Value>) -> Result<(), RuntimeError> {
+        let mut args = args.into_iter();
         for (index, arg_name) in self.args.iter().enumerate() {
-            let res = vm.eval(args[index].clone());
+            let res = args.next().unwrap();
+            let arg_type = self.arg_types.get(index).unwrap_or(&ArgType::Any);
+            if !arg_type.accepts(&res) {
+                return Err(RuntimeError::ParameterType {
+                    function: self.name.clone(),
+                    parameter: arg_name.clone(),
+                    expected: arg_type.name(),
+                    found: res.type_name(),
+                });
+            }
+
+            vm.inc_use_count(&res);
             vm.local.as_mut().unwrap().insert(arg_name.to_string(), res);
         }
 
         if self.has_variadic {
-            let mut variadic = vec![];
-            for arg in args.into_iter().skip(self.args.len()) {
-                let res = vm.eval(arg);
-                variadic.push(res);
-            }
-            vm.local.as_mut().unwrap().insert(VARIADIC_ARG_NAME.to_string(), Value::Array(variadic));
+            let varargs = Value::Array(args.collect());
+            vm.inc_use_count(&varargs);
+            vm.local.as_mut().unwrap().insert(VARIADIC_ARG_NAME.to_string(), varargs);
         }
+        Ok(())
+    }
 
-
-        let mut ret = None;
+    fn run_body(&self, vm: &mut VirtualMachine) -> Result<Option<Value>, RuntimeError> {
         for node in self.body.iter() {
             match *node {
                 Node::Return(ref value) => {
-                    ret = Some(vm.eval(value.clone()));
-                    break;
+                    return Ok(Some(vm.eval(value.clone())?));
                 }
                 _ => {
-                    vm.single_run(node.clone());
+                    vm.single_run(node.clone())?;
+                    if let Some(val) = vm.take_return() {
+                        return Ok(Some(val));
+                    }
                 }
             }
         }
 
-        vm.local = vm.locals.pop();
+        Ok(None)
+    }
+
+    /// Runs the call in the scope `with_new_scope` set up. A returned object keeps a use while that
+    /// scope is dropped, so one only a local held is not collected on the way out; `call` gives it
+    /// back once the scope is gone, leaving the caller with a plain value to store or discard.
+    fn bind_and_run(&self, vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Option<Value>, RuntimeError> {
+        let res = self.bind(vm, args).and_then(|_| self.run_body(vm));
+        if let Ok(Some(val)) = &res {
+            vm.inc_use_count(val);
+        }
+        res
+    }
+}
 
-        ret
+impl Callable for DefinedFunction {
+    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+        println!("Calling function: {}", self.name);
+        // Arguments are evaluated in the caller's scope, before the function's own is installed.
+        let args = args.into_iter().map(|arg| vm.eval(arg)).collect::<Result<Vec<_>, _>>()?;
+        let res = vm.with_new_scope(|vm| self.bind_and_run(vm, args));
+        release_returned(vm, &res);
+        res
     }
 
     fn args_len(&self) -> usize {
@@ -103,28 +191,102 @@ impl Callable for DefinedFunction {
     }
 }
 
+/// Gives back the use `bind_and_run` kept on a returned object, once the call's scope is gone.
+fn release_returned(vm: &mut VirtualMachine, res: &Result<Option<Value>, RuntimeError>) {
+    if let Ok(Some(val)) = res {
+        vm.release_use_count(val);
+    }
+}
+
+/// A function created by evaluating `Eval::Closure`. It runs on top of a copy of the local scope
+/// it was created in, and changes to those captured variables carry over to the next call.
+#[derive(Debug)]
+pub struct ClosureFunction {
+    function: DefinedFunction,
+    captured: RefCell<HashMap<String, Value>>,
+}
+
+impl ClosureFunction {
+    pub fn new(function: DefinedFunction, captured: HashMap<String, Value>) -> Self {
+        Self { function, captured: RefCell::new(captured) }
+    }
+}
+
+impl Callable for ClosureFunction {
+    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+        let args = args.into_iter().map(|arg| vm.eval(arg)).collect::<Result<Vec<_>, _>>()?;
+        let res = vm.with_new_scope(|vm| {
+            let captured = self.captured.borrow().clone();
+            for val in captured.values() {
+                vm.inc_use_count(val);
+            }
+            vm.local = Some(captured);
+            let res = self.function.bind_and_run(vm, args);
+
+            let local = vm.local.as_ref().unwrap();
+            for (name, val) in self.captured.borrow_mut().iter_mut() {
+                // A parameter shadowing a captured variable leaves the captured value alone.
+                if self.function.args.contains(name) {
+                    continue;
+                }
+                if let Some(new_val) = local.get(name) {
+                    *val = new_val.clone();
+                }
+            }
+            res
+        });
+        release_returned(vm, &res);
+        res
+    }
+
+    fn args_len(&self) -> usize {
+        self.function.args_len()
+    }
+
+    fn minimum_args_len(&self) -> usize {
+        self.function.minimum_args_len()
+    }
+
+    fn is_variadic(&self) -> bool {
+        self.function.is_variadic()
+    }
+
+    fn held_values(&self) -> Vec<Value> {
+        self.captured.borrow().values().cloned().collect()
+    }
+}
+
+
+pub type BuiltInFn = fn(&mut VirtualMachine, Vec<Eval>) -> Result<Option<Value>, RuntimeError>;
 
 #[derive(Clone)]
 pub struct BuiltInFunction {
     pub name: String,
     pub args_len: usize,
     pub is_variadic: bool,
-    pub func: fn(&mut VirtualMachine, Vec<Eval>) -> Option<Value>,
+    pub max_args: Option<usize>,
+    pub func: BuiltInFn,
 }
 
 impl BuiltInFunction {
-    pub fn new(name: String, args_len: usize, is_variadic: bool, func: fn(&mut VirtualMachine, Vec<Eval>) -> Option<Value>) -> Self {
+    pub fn new(name: String, args_len: usize, is_variadic: bool, func: BuiltInFn) -> Self {
         Self {
             name,
             args_len,
             is_variadic,
+            max_args: None,
             func
         }
     }
+
+    pub fn with_max_args(mut self, max_args: usize) -> Self {
+        self.max_args = Some(max_args);
+        self
+    }
 }
 
 impl Callable for BuiltInFunction {
-    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Option<Value> {
+    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
         (self.func)(vm, args)
     }
 
@@ -137,6 +299,10 @@ impl Callable for BuiltInFunction {
     fn is_variadic(&self) -> bool {
         self.is_variadic
     }
+
+    fn max_args_len(&self) -> Option<usize> {
+        self.max_args
+    }
 }
 
 impl Debug for BuiltInFunction {
@@ -145,462 +311,2426 @@ impl Debug for BuiltInFunction {
     }
 }
 
-pub fn println_array(val: &Vec<Value>){
-    print!("[");
+/// Applies `inner` and passes its result on to `outer`, created by the `compose` builtin.
+#[derive(Debug)]
+pub struct ComposedFunction {
+    pub outer: String,
+    pub inner: String,
+    pub args_len: usize,
+    pub is_variadic: bool,
+}
+
+impl Callable for ComposedFunction {
+    fn call(&self, vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+        let res = vm.call_function(self.inner.clone(), args)?.unwrap_or(Value::Unit);
+        Ok(Some(vm.call_with_values(&self.outer, vec![res])?))
+    }
+
+    fn args_len(&self) -> usize {
+        self.args_len
+    }
+
+    fn minimum_args_len(&self) -> usize { self.args_len }
+
+    fn is_variadic(&self) -> bool {
+        self.is_variadic
+    }
+
+    fn held_values(&self) -> Vec<Value> {
+        vec![Value::Function(self.outer.clone()), Value::Function(self.inner.clone())]
+    }
+}
+
+/// Formats a float so it always shows a decimal point, keeping it distinct from an int.
+pub fn format_float(val: f32) -> String {
+    if val.is_finite() && val.fract() == 0.0 {
+        format!("{:.1}", val)
+    } else {
+        format!("{}", val)
+    }
+}
+
+/// Formats a float with exactly `precision` decimals, or like `format_float` when `None`.
+pub fn format_float_with(val: f32, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) if val.is_finite() => format!("{:.1$}", val, precision),
+        _ => format_float(val)
+    }
+}
+
+pub fn println_array(out: &mut dyn Write, val: &[Value]) -> std::io::Result<()> {
+    write_array(out, val, None)
+}
+
+fn write_array(out: &mut dyn Write, val: &[Value], precision: Option<usize>) -> std::io::Result<()> {
+    write!(out, "[")?;
     for (i, val) in val.iter().enumerate() {
         if i != 0 {
-            print!(", ");
+            write!(out, ", ")?;
         }
         match val {
-            Value::Int(val) => { print!("{}", val) }
-            Value::Bool(val) => { print!("{}", val) }
-            Value::Float(val) => { print!("{}", val) }
-            Value::String(val) => { print!("\"{}\"", val) }
-            Value::Object(val) => { print!("Object <{:#08x}>", val) }
+            Value::Int(val) => { write!(out, "{}", val)? }
+            Value::Bool(val) => { write!(out, "{}", val)? }
+            Value::Float(val) => { write!(out, "{}", format_float_with(*val, precision))? }
+            Value::String(val) => { write!(out, "\"{}\"", val)? }
+            Value::Char(val) => { write!(out, "'{}'", val)? }
+            Value::Object(val) => { write!(out, "Object <{:#08x}>", val)? }
+            Value::Function(name) => { write!(out, "<function {}>", name)? }
+            Value::Null => { write!(out, "null")? }
+            Value::Unit => { write!(out, "()")? }
             Value::Array(val) => {
-                println_array(&val)
+                write_array(out, val, precision)?
             }
         }
     }
-    print!("]");
+    write!(out, "]")
 }
 
-pub fn builtin_print(vm: &mut VirtualMachine, args: Vec<Eval>) -> Option<Value> {
-    for (index, arg) in args.into_iter().enumerate() {
-        if index != 0 {
-            print!(" ");
+/// Formats a value the way `print` shows it.
+pub fn stringify(val: &Value) -> String {
+    stringify_with(val, None)
+}
+
+/// Like `stringify`, printing floats with `precision` decimals when set.
+pub fn stringify_with(val: &Value, precision: Option<usize>) -> String {
+    match val {
+        Value::Int(val) => val.to_string(),
+        Value::Bool(val) => val.to_string(),
+        Value::Float(val) => format_float_with(*val, precision),
+        Value::String(val) => val.clone(),
+        Value::Char(val) => val.to_string(),
+        Value::Object(val) => format!("Object <{:#08x}>", val),
+        Value::Function(name) => format!("<function {}>", name),
+        Value::Null => "null".to_string(),
+        Value::Unit => "()".to_string(),
+        Value::Array(val) => {
+            let mut out = vec![];
+            write_array(&mut out, val, precision).expect("writing to a Vec cannot fail");
+            String::from_utf8(out).unwrap()
         }
+    }
+}
 
-        let arg = vm.eval(arg);
-        match arg {
-            Value::Int(val) => { print!("{}", val) }
-            Value::Bool(val) => { print!("{}", val) }
-            Value::Float(val) => { print!("{}", val) }
-            Value::String(val) => { print!("{}", val) }
-            Value::Object(val) => { print!("Object <{:#08x}>", val) }
-            Value::Array(val) => {
-                println_array(&val)
-            }
+/// FNV-1a, which unlike `DefaultHasher` gives the same hash in every build and on every run.
+struct StableHasher(u64);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
         }
     }
-    None
+
+    /// Tags the bytes with the kind of value they came from and their length,
+    /// so e.g. `["ab"]` and `["a", "b"]` never feed the same bytes.
+    fn write_tagged(&mut self, tag: u8, bytes: &[u8]) {
+        self.write(&[tag]);
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+/// Coerces an int or float argument to a float.
+pub fn coerce_number(val: Value, function: &str, index: usize) -> Result<f32, RuntimeError> {
+    match val {
+        Value::Int(val) => Ok(val as f32),
+        Value::Float(val) => Ok(val),
+        val => Err(RuntimeError::argument_type(function, index, "number", &val))
+    }
+}
+
+/// Moves a char by `offset` code points, failing when that leaves the valid range.
+fn shift_char(c: char, offset: impl Into<i64>) -> Result<char, RuntimeError> {
+    let code = c as i64 + offset.into();
+    u32::try_from(code).ok()
+        .and_then(char::from_u32)
+        .ok_or(RuntimeError::InvalidCodePoint(code))
+}
+
+fn repeat_count(count: i32) -> Result<usize, RuntimeError> {
+    if count < 0 {
+        return Err(RuntimeError::NegativeCount(count));
+    }
+    Ok(count as usize)
+}
+
+fn print_args(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<String, RuntimeError> {
+    let mut parts = vec![];
+    for arg in args {
+        let arg = vm.eval(arg)?;
+        parts.push(vm.stringify(&arg));
+    }
+    Ok(parts.join(" "))
+}
+
+pub fn builtin_print(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let text = print_args(vm, args)?;
+    write!(vm.output, "{}", text)?;
+    Ok(None)
+}
+
+pub fn builtin_println(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    builtin_print(vm, args)?;
+    writeln!(vm.output)?;
+    Ok(None)
+}
+
+pub fn builtin_eprint(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let text = print_args(vm, args)?;
+    write!(vm.error_output, "{}", text)?;
+    Ok(None)
 }
 
-pub fn builtin_println(vm: &mut VirtualMachine, args: Vec<Eval>) -> Option<Value> {
-    builtin_print(vm, args);
-    println!();
-    None
+pub fn builtin_eprintln(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    builtin_eprint(vm, args)?;
+    writeln!(vm.error_output)?;
+    Ok(None)
 }
 
-pub fn builtin_input(_: &mut VirtualMachine, args: Vec<Eval>) -> Option<Value> {
+pub fn builtin_input(_: &mut VirtualMachine, _args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
     let mut input = String::new();
-    std::io::stdin().read_line(&mut input).unwrap();
-    Some(Value::String(input[..input.len() - 1].to_string()))
+    std::io::stdin().read_line(&mut input)?;
+    Ok(Some(Value::String(input[..input.len() - 1].to_string())))
 }
 
-pub fn builtin_input_print(vm: &mut VirtualMachine, args: Vec<Eval>) -> Option<Value> {
-    builtin_print(vm, args.clone());
-    std::io::stdout().flush().unwrap();
+pub fn builtin_input_print(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    builtin_print(vm, args.clone())?;
+    vm.output.flush()?;
     builtin_input(vm, args)
 }
 
+pub fn builtin_len(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    match vm.eval(args[0].clone())? {
+        Value::String(val) => Ok(Some(Value::Int(val.chars().count() as i32))),
+        Value::Array(val) => Ok(Some(Value::Int(val.len() as i32))),
+        val => Err(RuntimeError::argument_type("len", 0, "string or array", &val))
+    }
+}
 
-pub fn builtin_functions() -> Vec<BuiltInFunction>{
-    vec![
-        BuiltInFunction::new("print".to_string(), 0, true, builtin_print),
-        BuiltInFunction::new("println".to_string(), 0, true, builtin_println),
-        BuiltInFunction::new("input".to_string(), 0, false, builtin_input),
-        BuiltInFunction::new("input_print".to_string(), 0, true, builtin_input_print),
-    ]
+/// A new object with the fields of every argument, later ones winning. Nested objects are deep
+/// copied, so changing the result never reaches back into the sources.
+pub fn builtin_merge(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let mut fields = HashMap::new();
+    let mut copies = HashMap::new();
+    for (index, arg) in args.into_iter().enumerate() {
+        let id = vm.expect_object(arg, "merge", index)?;
+        for (name, val) in vm.objects[&id].fields.clone() {
+            let val = vm.copy_value(&val, &mut copies);
+            fields.insert(name, val);
+        }
+    }
+
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
 }
 
-impl Value {
-    pub fn as_eval(&mut self) -> Eval {
-        match self {
-            Value::Int(val) => { Eval::Int(*val) }
-            Value::Bool(val) => { Eval::Bool(*val) }
-            Value::Float(val) => { Eval::Float(*val) }
-            Value::String(val) => { Eval::String(val.clone()) }
-            Value::Object(val) => { Eval::Object(Box::new(Eval::Int(*val as i32))) }
-            Value::Array(val) => { Eval::Array(val.iter_mut().map(|x| x.as_eval()).collect()) }
+pub fn builtin_compose(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let outer = vm.expect_function(args[0].clone(), "compose", 0)?;
+    let inner = vm.expect_function(args[1].clone(), "compose", 1)?;
+
+    let name = format!("compose({}, {})", outer, inner);
+    let inner_function = vm.get_function(&inner)?;
+    let composed = ComposedFunction {
+        args_len: inner_function.args_len(),
+        is_variadic: inner_function.is_variadic(),
+        outer,
+        inner,
+    };
+    Ok(Some(vm.add_generated_function(name, Box::new(composed))))
+}
+
+pub fn builtin_pipe(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let mut args = args.into_iter().enumerate();
+    let mut value = vm.eval(args.next().unwrap().1)?;
+    for (index, arg) in args {
+        let name = vm.expect_function(arg, "pipe", index)?;
+        value = vm.call_with_values(&name, vec![value])?;
+    }
+    Ok(Some(value))
+}
+
+pub fn builtin_replace(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_string(args[0].clone(), "replace", 0)?;
+    let from = vm.expect_string(args[1].clone(), "replace", 1)?;
+    let to = vm.expect_string(args[2].clone(), "replace", 2)?;
+    if from.is_empty() {
+        return Err(RuntimeError::invalid_argument("replace", "the pattern to replace must not be empty"));
+    }
+    Ok(Some(Value::String(val.replace(&from, &to))))
+}
+
+pub fn builtin_trim_start(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_string(args[0].clone(), "trim_start", 0)?;
+    Ok(Some(Value::String(val.trim_start().to_string())))
+}
+
+pub fn builtin_trim_end(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_string(args[0].clone(), "trim_end", 0)?;
+    Ok(Some(Value::String(val.trim_end().to_string())))
+}
+
+pub fn builtin_starts_with(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_string(args[0].clone(), "starts_with", 0)?;
+    let prefix = vm.expect_string(args[1].clone(), "starts_with", 1)?;
+    Ok(Some(Value::Bool(val.starts_with(&prefix))))
+}
+
+pub fn builtin_ends_with(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_string(args[0].clone(), "ends_with", 0)?;
+    let suffix = vm.expect_string(args[1].clone(), "ends_with", 1)?;
+    Ok(Some(Value::Bool(val.ends_with(&suffix))))
+}
+
+pub fn builtin_object_from_pairs(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let keys = vm.expect_array(args[0].clone(), "object_from_pairs", 0)?;
+    let values = vm.expect_array(args[1].clone(), "object_from_pairs", 1)?;
+    if keys.len() != values.len() {
+        return Err(RuntimeError::invalid_argument("object_from_pairs", "keys and values must have the same length"));
+    }
+
+    let mut fields = HashMap::new();
+    for (key, val) in keys.into_iter().zip(values) {
+        match key {
+            Value::String(key) => { fields.insert(key, val); }
+            _ => { return Err(RuntimeError::invalid_argument("object_from_pairs", "keys must be strings")); }
         }
     }
 
-    pub fn as_int(&self) -> i32 {
-        match self {
-            Value::Int(val) => *val,
-            _ => panic!("Expected int")
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
+}
+
+pub fn builtin_unique(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "unique", 0)?;
+    let mut seen = HashSet::new();
+    let mut res = vec![];
+    for item in items {
+        if seen.insert(HashableValue::try_from(&item)?) {
+            res.push(item);
         }
     }
-    pub fn as_bool(&self) -> bool {
-        match self {
-            Value::Bool(val) => *val,
-            _ => panic!("Expected bool")
+    Ok(Some(Value::Array(res)))
+}
+
+pub fn builtin_group_by(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "group_by", 0)?;
+    let items = vm.expect_array(args[1].clone(), "group_by", 1)?;
+
+    let mut groups: HashMap<String, Vec<Value>> = HashMap::new();
+    for item in items {
+        let key = match vm.call_with_values(&name, vec![item.clone()])? {
+            Value::String(key) => key,
+            _ => return Err(RuntimeError::invalid_argument("group_by", "the key function must return a string"))
+        };
+        groups.entry(key).or_default().push(item);
+    }
+
+    let fields = groups.into_iter().map(|(key, items)| (key, Value::Array(items))).collect();
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
+}
+
+pub fn builtin_exists(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_string(args[0].clone(), "exists", 0)?;
+    Ok(Some(Value::Bool(vm.lookup_variable(&name).is_some())))
+}
+
+/// Marks an object, and every object reachable from its fields, as immutable.
+pub fn builtin_freeze(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let id = vm.expect_object(args[0].clone(), "freeze", 0)?;
+    let mut pending = vec![id];
+    while let Some(id) = pending.pop() {
+        if !vm.frozen_objects.insert(id) {
+            continue;
+        }
+        if let Some(obj) = vm.objects.get(&id) {
+            for val in obj.fields.values() {
+                if let Value::Object(field_id) = val {
+                    pending.push(*field_id);
+                }
+            }
         }
     }
-    pub fn as_float(&self) -> f32 {
-        match self {
-            Value::Float(val) => *val,
-            _ => panic!("Expected float")
+    Ok(Some(Value::Object(id)))
+}
+
+/// Right-aligns the stringified value to `width`, or left-aligns it if `width` is negative.
+pub fn builtin_pad(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    let val = vm.stringify(&val);
+    let width = vm.expect_int(args[1].clone(), "pad", 1)?;
+
+    let padded = if width < 0 {
+        format!("{:<1$}", val, width.unsigned_abs() as usize)
+    } else {
+        format!("{:>1$}", val, width as usize)
+    };
+    Ok(Some(Value::String(padded)))
+}
+
+pub fn builtin_fixed(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_float(args[0].clone(), "fixed", 0)?;
+    let decimals = repeat_count(vm.expect_int(args[1].clone(), "fixed", 1)?)?;
+    Ok(Some(Value::String(format!("{:.1$}", val, decimals))))
+}
+
+pub fn builtin_abs_diff(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let lhs = vm.eval(args[0].clone())?;
+    let rhs = vm.eval(args[1].clone())?;
+    match (lhs, rhs) {
+        (Value::Int(l), Value::Int(r)) => {
+            let diff = i32::try_from(l.abs_diff(r))
+                .map_err(|_| RuntimeError::invalid_argument("abs_diff", "the difference does not fit in an int"))?;
+            Ok(Some(Value::Int(diff)))
+        }
+        (lhs, rhs) => {
+            let l = coerce_number(lhs, "abs_diff", 0)?;
+            let r = coerce_number(rhs, "abs_diff", 1)?;
+            Ok(Some(Value::Float((l - r).abs())))
         }
     }
-    pub fn as_string(&self) -> String {
-        match self {
-            Value::String(val) => val.clone(),
-            _ => panic!("Expected string")
+}
+
+pub fn builtin_clamp01(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_number(args[0].clone(), "clamp01", 0)?;
+    Ok(Some(Value::Float(val.clamp(0.0, 1.0))))
+}
+
+/// Interpolates from `a` to `b`, extrapolating past either end when `t` is outside [0, 1].
+pub fn builtin_lerp(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let a = vm.expect_number(args[0].clone(), "lerp", 0)?;
+    let b = vm.expect_number(args[1].clone(), "lerp", 1)?;
+    let t = vm.expect_number(args[2].clone(), "lerp", 2)?;
+    Ok(Some(Value::Float(a + (b - a) * t)))
+}
+
+/// Copies a template object, replacing the fields given in the overrides object.
+/// Unlike `merge`, overrides may only set fields the template already declares.
+pub fn builtin_from_template(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let template = vm.expect_object(args[0].clone(), "from_template", 0)?;
+    let overrides = vm.expect_object(args[1].clone(), "from_template", 1)?;
+
+    let mut fields = vm.objects.get(&template).unwrap().fields.clone();
+    for (name, val) in &vm.objects.get(&overrides).unwrap().fields {
+        match fields.get_mut(name) {
+            Some(field) => { *field = val.clone(); }
+            None => {
+                let message = format!("the template has no field {}", name);
+                return Err(RuntimeError::invalid_argument("from_template", &message));
+            }
         }
     }
+
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
 }
 
-#[derive(Debug)]
-pub struct Object {
-    pub fields: HashMap<String, Value>,
+/// Maps each element through a function, dropping the results that are null.
+pub fn builtin_filter_map(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "filter_map", 0)?;
+    let items = vm.expect_array(args[1].clone(), "filter_map", 1)?;
+
+    let mut res = vec![];
+    for item in items {
+        match vm.call_with_values(&name, vec![item])? {
+            Value::Null => {}
+            val => res.push(val)
+        }
+    }
+    Ok(Some(Value::Array(res)))
 }
 
-impl Object {
-    fn new(fields: HashMap<String, Value>) -> Self {
-        Object {
-            fields
+/// Finds the first element whose key is the most extreme in the `wanted` direction.
+fn extreme_by(vm: &mut VirtualMachine, args: Vec<Eval>, function: &str, wanted: Ordering) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), function, 0)?;
+    let items = vm.expect_array(args[1].clone(), function, 1)?;
+
+    let mut best: Option<(Value, Value)> = None;
+    for item in items {
+        let key = vm.call_with_values(&name, vec![item.clone()])?;
+        let replace = match &best {
+            None => true,
+            Some((best_key, _)) => match key.partial_cmp(best_key) {
+                Some(ordering) => ordering == wanted,
+                None => return Err(RuntimeError::invalid_argument(function, "keys cannot be compared"))
+            }
+        };
+        if replace {
+            best = Some((key, item));
         }
     }
+
+    match best {
+        Some((_, item)) => Ok(Some(item)),
+        None => Err(RuntimeError::invalid_argument(function, "array must not be empty"))
+    }
 }
 
-pub enum GcApproach {
-    None,
-    ReferenceCounting,
-    Custom { func: fn(&mut VirtualMachine, Vec<String>) }
+pub fn builtin_max_by(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    extreme_by(vm, args, "max_by", Ordering::Greater)
+}
+
+pub fn builtin_min_by(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    extreme_by(vm, args, "min_by", Ordering::Less)
+}
+
+/// Folds left to right like a reduce, returning every accumulator state after `init`.
+/// `scan(add, [1, 2, 3], 0)` gives `[1, 3, 6]`, so the result is as long as the input.
+pub fn builtin_scan(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "scan", 0)?;
+    let items = vm.expect_array(args[1].clone(), "scan", 1)?;
+    let mut acc = vm.eval(args[2].clone())?;
+
+    let mut res = Vec::with_capacity(items.len());
+    for item in items {
+        acc = vm.call_with_values(&name, vec![acc, item])?;
+        res.push(acc.clone());
+    }
+    Ok(Some(Value::Array(res)))
 }
 
-impl Debug for GcApproach {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            GcApproach::None => { write!(f, "None") }
-            GcApproach::ReferenceCounting => { write!(f, "ReferenceCounting") }
-            GcApproach::Custom { .. } => { write!(f, "Custom") }
+pub fn builtin_rename_field(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let id = vm.expect_object(args[0].clone(), "rename_field", 0)?;
+    let old = vm.expect_string(args[1].clone(), "rename_field", 1)?;
+    let new = vm.expect_string(args[2].clone(), "rename_field", 2)?;
+    if vm.frozen_objects.contains(&id) {
+        return Err(RuntimeError::FrozenObject(id));
+    }
+
+    let fields = &mut vm.objects.get_mut(&id).unwrap().fields;
+    if fields.contains_key(&new) {
+        return Err(RuntimeError::invalid_argument("rename_field", &format!("field {} already exists", new)));
+    }
+    match fields.remove(&old) {
+        Some(val) => { fields.insert(new, val); }
+        None => {
+            return Err(RuntimeError::invalid_argument("rename_field", &format!("field {} does not exist", old)));
+        }
+    }
+    Ok(None)
+}
+
+pub fn builtin_array_with_capacity(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let capacity = repeat_count(vm.expect_int(args[0].clone(), "array_with_capacity", 0)?)?;
+    Ok(Some(Value::Array(Vec::with_capacity(capacity))))
+}
+
+/// Appends to the array held by the named variable in place, instead of rebuilding it.
+pub fn builtin_push(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_string(args[0].clone(), "push", 0)?;
+    let val = vm.eval(args[1].clone())?;
+    match vm.lookup_variable_mut(&name) {
+        Some(Value::Array(items)) => {
+            items.push(val);
+            Ok(None)
+        }
+        Some(other) => {
+            let found = other.clone();
+            Err(RuntimeError::argument_type("push", 0, "name of an array variable", &found))
+        }
+        None => Err(RuntimeError::invalid_argument("push", &format!("variable {} does not exist", name)))
+    }
+}
+
+/// Returns the first argument that isn't null, leaving the rest unevaluated.
+pub fn builtin_coalesce(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    for arg in args {
+        match vm.eval(arg)? {
+            Value::Null => {}
+            val => return Ok(Some(val))
+        }
+    }
+    Ok(Some(Value::Null))
+}
+
+pub fn builtin_fill(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    let count = repeat_count(vm.expect_int(args[1].clone(), "fill", 1)?)?;
+    Ok(Some(Value::Array(vec![val; count])))
+}
+
+/// Truncates the array to `n` elements, or extends it with copies of the fill value.
+pub fn builtin_resize(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let mut items = vm.expect_array(args[0].clone(), "resize", 0)?;
+    let len = repeat_count(vm.expect_int(args[1].clone(), "resize", 1)?)?;
+    let fill = vm.eval(args[2].clone())?;
+    items.resize(len, fill);
+    Ok(Some(Value::Array(items)))
+}
+
+pub fn builtin_chr(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let code = vm.expect_int(args[0].clone(), "chr", 0)?;
+    Ok(Some(Value::Char(shift_char('\0', code)?)))
+}
+
+pub fn builtin_ord(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    match vm.eval(args[0].clone())? {
+        Value::Char(c) => Ok(Some(Value::Int(c as i32))),
+        val => Err(RuntimeError::argument_type("ord", 0, "char", &val))
+    }
+}
+
+fn call_predicate(vm: &mut VirtualMachine, function: &str, name: &str, item: Value) -> Result<bool, RuntimeError> {
+    match vm.call_with_values(name, vec![item])? {
+        Value::Bool(val) => Ok(val),
+        _ => Err(RuntimeError::invalid_argument(function, "the predicate must return a bool"))
+    }
+}
+
+/// Returns the first element the predicate accepts, or null, without testing the rest.
+pub fn builtin_find(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "find", 0)?;
+    let items = vm.expect_array(args[1].clone(), "find", 1)?;
+    for item in items {
+        if call_predicate(vm, "find", &name, item.clone())? {
+            return Ok(Some(item));
+        }
+    }
+    Ok(Some(Value::Null))
+}
+
+pub fn builtin_any(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "any", 0)?;
+    let items = vm.expect_array(args[1].clone(), "any", 1)?;
+    for item in items {
+        if call_predicate(vm, "any", &name, item)? {
+            return Ok(Some(Value::Bool(true)));
+        }
+    }
+    Ok(Some(Value::Bool(false)))
+}
+
+pub fn builtin_all(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "all", 0)?;
+    let items = vm.expect_array(args[1].clone(), "all", 1)?;
+    for item in items {
+        if !call_predicate(vm, "all", &name, item)? {
+            return Ok(Some(Value::Bool(false)));
+        }
+    }
+    Ok(Some(Value::Bool(true)))
+}
+
+/// Lists an object's fields as `[key, value]` pairs sorted by key.
+pub fn builtin_entries(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let id = vm.expect_object(args[0].clone(), "entries", 0)?;
+    let mut entries: Vec<(&String, &Value)> = vm.objects.get(&id).unwrap().fields.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    let entries = entries.into_iter()
+        .map(|(key, val)| Value::Array(vec![Value::String(key.clone()), val.clone()]))
+        .collect();
+    Ok(Some(Value::Array(entries)))
+}
+
+pub fn builtin_from_entries(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let entries = vm.expect_array(args[0].clone(), "from_entries", 0)?;
+
+    let mut fields = HashMap::new();
+    for entry in entries {
+        match entry {
+            Value::Array(pair) => match <[Value; 2]>::try_from(pair) {
+                Ok([Value::String(key), val]) => { fields.insert(key, val); }
+                _ => { return Err(RuntimeError::invalid_argument("from_entries", "entries must be [string, value] pairs")); }
+            },
+            _ => { return Err(RuntimeError::invalid_argument("from_entries", "entries must be [string, value] pairs")); }
+        }
+    }
+
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
+}
+
+
+pub fn builtin_str(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    Ok(Some(Value::String(vm.stringify(&val))))
+}
+
+/// Names of every registered function, builtin or defined, sorted.
+pub fn builtin_functions_list(vm: &mut VirtualMachine, _args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    // `functions()` itself is taken out of the map while it runs.
+    let mut names: Vec<String> = vm.functions.keys().cloned().chain(["functions".to_string()]).collect();
+    names.sort();
+    names.dedup();
+    Ok(Some(Value::Array(names.into_iter().map(Value::String).collect())))
+}
+
+/// Deep copies an object argument so the callee cannot modify the caller's object.
+/// Other values are already passed by value and come back unchanged.
+pub fn builtin_pass_by_value(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    Ok(Some(vm.copy_value(&val, &mut HashMap::new())))
+}
+
+/// Counts of the VM's live objects, globals, saved scopes and functions, as a new object.
+pub fn builtin_vm_stats(vm: &mut VirtualMachine, _args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let mut fields = HashMap::new();
+    fields.insert("object_count".to_string(), Value::Int(vm.objects.len() as i32));
+    fields.insert("global_count".to_string(), Value::Int(vm.global_variables.len() as i32));
+    fields.insert("scope_depth".to_string(), Value::Int(vm.locals.len() as i32));
+    // `vm_stats()` itself is taken out of the map while it runs.
+    fields.insert("function_count".to_string(), Value::Int(vm.functions.len() as i32 + 1));
+
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
+}
+
+pub fn builtin_nan(_: &mut VirtualMachine, _args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    Ok(Some(Value::Float(f32::NAN)))
+}
+
+pub fn builtin_inf(_: &mut VirtualMachine, _args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    Ok(Some(Value::Float(f32::INFINITY)))
+}
+
+pub fn builtin_is_nan(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_number(args[0].clone(), "is_nan", 0)?;
+    Ok(Some(Value::Bool(val.is_nan())))
+}
+
+/// False for NaN and both infinities. Ints are always finite.
+pub fn builtin_is_finite(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_number(args[0].clone(), "is_finite", 0)?;
+    Ok(Some(Value::Bool(val.is_finite())))
+}
+
+/// Fills `{}` placeholders with the arguments in order, or `{N}` placeholders with argument N.
+/// One template may not mix the two styles. `{{` and `}}` are literal braces.
+pub fn builtin_format(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let template = vm.expect_string(args[0].clone(), "format", 0)?;
+    let mut values = vec![];
+    for arg in args.into_iter().skip(1) {
+        let val = vm.eval(arg)?;
+        values.push(vm.stringify(&val));
+    }
+
+    let mut out = String::new();
+    let mut next = 0;
+    let mut positional = None;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut spec = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => spec.push(c),
+                        None => { return Err(RuntimeError::invalid_argument("format", "unclosed placeholder")); }
+                    }
+                }
+
+                let is_positional = !spec.is_empty();
+                if *positional.get_or_insert(is_positional) != is_positional {
+                    return Err(RuntimeError::invalid_argument("format", "cannot mix {} and {N} placeholders"));
+                }
+
+                let index = if is_positional {
+                    spec.parse::<usize>().map_err(|_| {
+                        RuntimeError::invalid_argument("format", &format!("invalid placeholder {{{}}}", spec))
+                    })?
+                } else {
+                    next += 1;
+                    next - 1
+                };
+                match values.get(index) {
+                    Some(val) => out.push_str(val),
+                    None => {
+                        let message = format!("no argument for placeholder {}", index);
+                        return Err(RuntimeError::invalid_argument("format", &message));
+                    }
+                }
+            }
+            '}' => { return Err(RuntimeError::invalid_argument("format", "unmatched }")); }
+            c => out.push(c)
+        }
+    }
+
+    Ok(Some(Value::String(out)))
+}
+
+/// Splits on `\n`, dropping a `\r` before it. A trailing newline does not start another line.
+pub fn builtin_split_lines(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let text = vm.expect_string(args[0].clone(), "split_lines", 0)?;
+    Ok(Some(Value::Array(text.lines().map(|line| Value::String(line.to_string())).collect())))
+}
+
+pub fn builtin_join_lines(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "join_lines", 0)?;
+
+    let mut lines = vec![];
+    for item in items {
+        match item {
+            Value::String(line) => lines.push(line),
+            val => { return Err(RuntimeError::argument_type("join_lines", 0, "array of strings", &val)); }
+        }
+    }
+    Ok(Some(Value::String(lines.join("\n"))))
+}
+
+pub fn builtin_deep_equals(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let lhs = vm.eval(args[0].clone())?;
+    let rhs = vm.eval(args[1].clone())?;
+    Ok(Some(Value::Bool(vm.deep_equals(&lhs, &rhs, &mut HashSet::new()))))
+}
+
+/// Index of the first number that is the most extreme in the `wanted` direction.
+fn arg_extreme(vm: &mut VirtualMachine, args: Vec<Eval>, function: &str, wanted: Ordering) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), function, 0)?;
+
+    let mut best: Option<(usize, f32)> = None;
+    for (index, item) in items.into_iter().enumerate() {
+        let val = coerce_number(item, function, 0)?;
+        match best {
+            Some((_, best_val)) if val.partial_cmp(&best_val) != Some(wanted) => {}
+            _ => best = Some((index, val))
+        }
+    }
+
+    match best {
+        Some((index, _)) => Ok(Some(Value::Int(index as i32))),
+        None => Err(RuntimeError::invalid_argument(function, "the array is empty"))
+    }
+}
+
+pub fn builtin_argmin(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    arg_extreme(vm, args, "argmin", Ordering::Less)
+}
+
+pub fn builtin_argmax(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    arg_extreme(vm, args, "argmax", Ordering::Greater)
+}
+
+/// Returns the int unchanged if it fits in a byte, 0 to 255.
+pub fn builtin_to_byte(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_int(args[0].clone(), "to_byte", 0)?;
+    match u8::try_from(val) {
+        Ok(byte) => Ok(Some(Value::Int(byte as i32))),
+        Err(_) => Err(RuntimeError::invalid_argument("to_byte", &format!("{} does not fit in a byte", val)))
+    }
+}
+
+/// Encodes a string as an array of its UTF-8 bytes.
+pub fn builtin_byte_array(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let text = vm.expect_string(args[0].clone(), "byte_array", 0)?;
+    Ok(Some(Value::Array(text.bytes().map(|byte| Value::Int(byte as i32)).collect())))
+}
+
+/// Prints rows of equal length as left-aligned columns separated by two spaces.
+pub fn builtin_print_table(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let rows = vm.expect_array(args[0].clone(), "print_table", 0)?;
+
+    let mut cells: Vec<Vec<String>> = vec![];
+    for row in rows {
+        match row {
+            Value::Array(row) => cells.push(row.iter().map(|cell| vm.stringify(cell)).collect()),
+            val => { return Err(RuntimeError::argument_type("print_table", 0, "array of arrays", &val)); }
+        }
+    }
+    if cells.iter().any(|row| row.len() != cells[0].len()) {
+        return Err(RuntimeError::invalid_argument("print_table", "all rows must have the same length"));
+    }
+
+    let columns = cells.first().map_or(0, |row| row.len());
+    let widths: Vec<usize> = (0..columns)
+        .map(|column| cells.iter().map(|row| row[column].chars().count()).max().unwrap_or(0))
+        .collect();
+
+    for row in cells {
+        let line: Vec<String> = row.iter().zip(&widths)
+            .map(|(cell, width)| format!("{:<1$}", cell, width))
+            .collect();
+        writeln!(vm.output, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(None)
+}
+
+/// Returns the element at `index`, counting from the end when negative, or `default` when out of range.
+pub fn builtin_get(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "get", 0)?;
+    let index = vm.expect_int(args[1].clone(), "get", 1)?;
+    let default = vm.eval(args[2].clone())?;
+
+    let index = if index < 0 {
+        items.len().checked_sub(index.unsigned_abs() as usize)
+    } else {
+        Some(index as usize)
+    };
+    Ok(Some(index.and_then(|index| items.into_iter().nth(index)).unwrap_or(default)))
+}
+
+/// Collects the named field of every object in the array.
+pub fn builtin_pluck(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "pluck", 0)?;
+    let field = vm.expect_string(args[1].clone(), "pluck", 1)?;
+
+    let mut res = vec![];
+    for item in items {
+        let id = match item {
+            Value::Object(id) => id,
+            val => { return Err(RuntimeError::argument_type("pluck", 0, "array of objects", &val)); }
+        };
+        match vm.objects[&id].fields.get(&field) {
+            Some(val) => res.push(val.clone()),
+            None => {
+                let message = format!("object {} has no field {}", id, field);
+                return Err(RuntimeError::invalid_argument("pluck", &message));
+            }
+        }
+    }
+    Ok(Some(Value::Array(res)))
+}
+
+/// Calls a function with the elements of an array as its arguments.
+pub fn builtin_apply(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "apply", 0)?;
+    let values = vm.expect_array(args[1].clone(), "apply", 1)?;
+
+    let function = vm.get_function(&name)?;
+    let accepted = if function.is_variadic() {
+        values.len() >= function.minimum_args_len() && function.max_args_len().is_none_or(|max| values.len() <= max)
+    } else {
+        values.len() == function.args_len()
+    };
+    if !accepted {
+        let message = format!("{} cannot take {} arguments", name, values.len());
+        return Err(RuntimeError::invalid_argument("apply", &message));
+    }
+
+    Ok(Some(vm.call_with_values(&name, values)?))
+}
+
+/// Blocks for `ms` milliseconds. A sleep running past the VM's deadline wakes at the deadline and times out.
+pub fn builtin_sleep(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let ms = vm.expect_int(args[0].clone(), "sleep", 0)?;
+    if ms < 0 {
+        return Err(RuntimeError::invalid_argument("sleep", "the duration must not be negative"));
+    }
+
+    let duration = Duration::from_millis(ms as u64);
+    match vm.deadline {
+        Some(deadline) if Instant::now() + duration > deadline => {
+            std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            Err(RuntimeError::Timeout)
+        }
+        _ => {
+            std::thread::sleep(duration);
+            Ok(None)
+        }
+    }
+}
+
+/// Reads an environment variable, returning the optional default or null when it is not set.
+pub fn builtin_env(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    if vm.sandboxed {
+        return Err(RuntimeError::Sandboxed("env"));
+    }
+
+    let name = vm.expect_string(args[0].clone(), "env", 0)?;
+    match std::env::var(&name) {
+        Ok(val) => Ok(Some(Value::String(val))),
+        Err(_) => match args.get(1) {
+            Some(default) => Ok(Some(vm.eval(default.clone())?)),
+            None => Ok(Some(Value::Null))
+        }
+    }
+}
+
+pub fn builtin_args(vm: &mut VirtualMachine, _args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    Ok(Some(Value::Array(vm.program_args.iter().cloned().map(Value::String).collect())))
+}
+
+/// Hashes a value by its contents, so structurally equal values hash equally, even across runs.
+pub fn builtin_hash(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    let mut hasher = StableHasher::new();
+    vm.hash_value(&val, &mut hasher, &mut vec![]);
+    Ok(Some(Value::Int((hasher.0 ^ (hasher.0 >> 32)) as i32)))
+}
+
+/// The float's IEEE 754 bits reinterpreted as an int, so floats with the sign bit set give negative ints.
+pub fn builtin_float_to_bits(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.expect_float(args[0].clone(), "float_to_bits", 0)?;
+    Ok(Some(Value::Int(val.to_bits() as i32)))
+}
+
+pub fn builtin_bits_to_float(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let bits = vm.expect_int(args[0].clone(), "bits_to_float", 0)?;
+    Ok(Some(Value::Float(f32::from_bits(bits as u32))))
+}
+
+/// Splits an array into `[accepted, rejected]` by a predicate, keeping the order within each.
+pub fn builtin_partition(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "partition", 0)?;
+    let items = vm.expect_array(args[1].clone(), "partition", 1)?;
+
+    let (mut accepted, mut rejected) = (vec![], vec![]);
+    for item in items {
+        if call_predicate(vm, "partition", &name, item.clone())? {
+            accepted.push(item);
+        } else {
+            rejected.push(item);
+        }
+    }
+    Ok(Some(Value::Array(vec![Value::Array(accepted), Value::Array(rejected)])))
+}
+
+/// The first `n` elements, or the whole array if it is shorter.
+pub fn builtin_take(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "take", 0)?;
+    let count = repeat_count(vm.expect_int(args[1].clone(), "take", 1)?)?;
+    Ok(Some(Value::Array(items.into_iter().take(count).collect())))
+}
+
+/// Everything after the first `n` elements, or an empty array if it is shorter.
+pub fn builtin_drop(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "drop", 0)?;
+    let count = repeat_count(vm.expect_int(args[1].clone(), "drop", 1)?)?;
+    Ok(Some(Value::Array(items.into_iter().skip(count).collect())))
+}
+
+/// Number of leading elements the predicate accepts, not testing any after the first rejected one.
+fn leading_accepted(vm: &mut VirtualMachine, function: &str, name: &str, items: &[Value]) -> Result<usize, RuntimeError> {
+    for (index, item) in items.iter().enumerate() {
+        if !call_predicate(vm, function, name, item.clone())? {
+            return Ok(index);
+        }
+    }
+    Ok(items.len())
+}
+
+pub fn builtin_take_while(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "take_while", 0)?;
+    let mut items = vm.expect_array(args[1].clone(), "take_while", 1)?;
+    let count = leading_accepted(vm, "take_while", &name, &items)?;
+    items.truncate(count);
+    Ok(Some(Value::Array(items)))
+}
+
+pub fn builtin_drop_while(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let name = vm.expect_function(args[0].clone(), "drop_while", 0)?;
+    let mut items = vm.expect_array(args[1].clone(), "drop_while", 1)?;
+    let count = leading_accepted(vm, "drop_while", &name, &items)?;
+    items.drain(..count);
+    Ok(Some(Value::Array(items)))
+}
+
+/// Reads the array and sub-array size shared by `chunk` and `windows`.
+fn sized_slices(vm: &mut VirtualMachine, args: Vec<Eval>, function: &str) -> Result<(Vec<Value>, usize), RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), function, 0)?;
+    let size = vm.expect_int(args[1].clone(), function, 1)?;
+    if size <= 0 {
+        return Err(RuntimeError::invalid_argument(function, "the size must be positive"));
+    }
+    Ok((items, size as usize))
+}
+
+/// Splits an array into consecutive sub-arrays of `n` elements, the last one possibly shorter.
+pub fn builtin_chunk(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let (items, size) = sized_slices(vm, args, "chunk")?;
+    Ok(Some(Value::Array(items.chunks(size).map(|chunk| Value::Array(chunk.to_vec())).collect())))
+}
+
+/// Every run of `n` consecutive elements, overlapping. Empty if the array is shorter than `n`.
+pub fn builtin_windows(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let (items, size) = sized_slices(vm, args, "windows")?;
+    Ok(Some(Value::Array(items.windows(size).map(|window| Value::Array(window.to_vec())).collect())))
+}
+
+/// Pads a string to `width` chars with the optional fill char, a space by default, putting
+/// `left_share` of the padding on the left, rounded down. Longer strings come back unchanged.
+fn justify(vm: &mut VirtualMachine, args: Vec<Eval>, function: &str, left_share: f32) -> Result<Option<Value>, RuntimeError> {
+    let text = vm.expect_string(args[0].clone(), function, 0)?;
+    let width = vm.expect_int(args[1].clone(), function, 1)?;
+    let fill = match args.get(2) {
+        None => ' ',
+        Some(fill) => match vm.eval(fill.clone())? {
+            Value::Char(fill) => fill,
+            Value::String(fill) if fill.chars().count() == 1 => fill.chars().next().unwrap(),
+            val => { return Err(RuntimeError::argument_type(function, 2, "single char", &val)); }
+        }
+    };
+
+    let padding = (width.max(0) as usize).saturating_sub(text.chars().count());
+    let left = (padding as f32 * left_share) as usize;
+    let mut res: String = std::iter::repeat_n(fill, left).collect();
+    res.push_str(&text);
+    res.extend(std::iter::repeat_n(fill, padding - left));
+    Ok(Some(Value::String(res)))
+}
+
+pub fn builtin_ljust(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    justify(vm, args, "ljust", 0.0)
+}
+
+pub fn builtin_rjust(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    justify(vm, args, "rjust", 1.0)
+}
+
+/// Centers the string, with the odd char of padding, if any, on the right.
+pub fn builtin_center(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    justify(vm, args, "center", 0.5)
+}
+
+/// Converts to an int like `Eval::Cast`, so `int("  -42 ")` is -42 and `int("4x")` fails.
+pub fn builtin_int(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    Ok(Some(vm.cast(val, TypeName::Int)?))
+}
+
+/// Converts to a float like `Eval::Cast`, accepting signs and exponents such as `"+3.5"` and `"1e3"`.
+pub fn builtin_float(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    Ok(Some(vm.cast(val, TypeName::Float)?))
+}
+
+pub fn builtin_seed(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let seed = vm.expect_int(args[0].clone(), "seed", 0)?;
+    vm.seed_random(seed as u64);
+    Ok(None)
+}
+
+/// A float in `[0, 1)` from the VM's PRNG.
+pub fn builtin_random(vm: &mut VirtualMachine, _args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    Ok(Some(Value::Float((vm.next_random() >> 40) as f32 / (1u64 << 24) as f32)))
+}
+
+/// A randomly permuted copy of the array, reproducible under a fixed `seed`.
+pub fn builtin_shuffle(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let mut items = vm.expect_array(args[0].clone(), "shuffle", 0)?;
+    for i in (1..items.len()).rev() {
+        let j = vm.random_below(i + 1);
+        items.swap(i, j);
+    }
+    Ok(Some(Value::Array(items)))
+}
+
+pub fn builtin_choice(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let mut items = vm.expect_array(args[0].clone(), "choice", 0)?;
+    if items.is_empty() {
+        return Err(RuntimeError::invalid_argument("choice", "the array must not be empty"));
+    }
+    let index = vm.random_below(items.len());
+    Ok(Some(items.swap_remove(index)))
+}
+
+/// `k` elements from distinct positions of the array, in random order.
+pub fn builtin_sample(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let mut items = vm.expect_array(args[0].clone(), "sample", 0)?;
+    let k = vm.expect_int(args[1].clone(), "sample", 1)?;
+    if k < 0 || k as usize > items.len() {
+        return Err(RuntimeError::invalid_argument("sample", "the count must be between 0 and the array length"));
+    }
+
+    for i in 0..k as usize {
+        let j = i + vm.random_below(items.len() - i);
+        items.swap(i, j);
+    }
+    items.truncate(k as usize);
+    Ok(Some(Value::Array(items)))
+}
+
+/// Recursively copies arrays and objects, giving every object reached a fresh id. An object
+/// reached twice is copied once, so shared references and cycles survive the copy.
+pub fn builtin_deep_copy(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let val = vm.eval(args[0].clone())?;
+    Ok(Some(vm.copy_value(&val, &mut HashMap::new())))
+}
+
+/// Tallies the array into a new object keyed by each distinct element as `str` shows it.
+pub fn builtin_frequencies(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "frequencies", 0)?;
+
+    let mut counts: HashMap<String, i32> = HashMap::new();
+    for item in &items {
+        *counts.entry(vm.stringify(item)).or_default() += 1;
+    }
+    let fields = counts.into_iter().map(|(key, count)| (key, Value::Int(count))).collect();
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
+}
+
+fn flatten_fields(vm: &VirtualMachine, id: usize, prefix: &str, out: &mut HashMap<String, Value>, visiting: &mut Vec<usize>) -> Result<(), RuntimeError> {
+    if visiting.contains(&id) {
+        return Err(RuntimeError::invalid_argument("flatten_object", "the object must not contain cycles"));
+    }
+    visiting.push(id);
+
+    for (name, val) in &vm.objects[&id].fields {
+        let path = format!("{}{}", prefix, name);
+        match val {
+            Value::Object(nested) => flatten_fields(vm, *nested, &format!("{}.", path), out, visiting)?,
+            val => { out.insert(path, val.clone()); }
+        }
+    }
+
+    visiting.pop();
+    Ok(())
+}
+
+/// A new object with the fields of nested objects lifted to dotted keys, so `{a: {b: 1}}`
+/// becomes `{"a.b": 1}`. Arrays are kept as values and empty nested objects disappear.
+pub fn builtin_flatten_object(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let id = vm.expect_object(args[0].clone(), "flatten_object", 0)?;
+    let mut fields = HashMap::new();
+    flatten_fields(vm, id, "", &mut fields, &mut vec![])?;
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
+}
+
+/// Evaluates the optional message argument, falling back to `default`.
+fn assertion_message(vm: &mut VirtualMachine, message: Option<&Eval>, default: String) -> Result<String, RuntimeError> {
+    match message {
+        Some(message) => {
+            let message = vm.eval(message.clone())?;
+            Ok(vm.stringify(&message))
+        }
+        None => Ok(default)
+    }
+}
+
+pub fn builtin_assert(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let condition = vm.expect_bool(args[0].clone(), "assert", 0)?;
+    let message = assertion_message(vm, args.get(1), "condition was false".to_string())?;
+    vm.record_assertion(condition, message)?;
+    Ok(None)
+}
+
+/// Asserts the two values are equal, comparing objects by their fields like `deep_equals`.
+pub fn builtin_assert_eq(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let lhs = vm.eval(args[0].clone())?;
+    let rhs = vm.eval(args[1].clone())?;
+    let equal = vm.deep_equals(&lhs, &rhs, &mut HashSet::new());
+    let default = format!("{} != {}", vm.stringify(&lhs), vm.stringify(&rhs));
+    let message = assertion_message(vm, args.get(2), default)?;
+    vm.record_assertion(equal, message)?;
+    Ok(None)
+}
+
+/// A new object mapping `"0"`, `"1"`, ... to the array's elements.
+pub fn builtin_array_to_object(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let items = vm.expect_array(args[0].clone(), "array_to_object", 0)?;
+    let fields = items.into_iter().enumerate().map(|(index, item)| (index.to_string(), item)).collect();
+    Ok(Some(Value::Object(vm.allocate_object(fields))))
+}
+
+/// The reverse of `array_to_object`, failing unless the keys are exactly `"0"` up to the field count.
+pub fn builtin_object_to_array(vm: &mut VirtualMachine, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+    let id = vm.expect_object(args[0].clone(), "object_to_array", 0)?;
+    let fields = &vm.objects[&id].fields;
+    let items = (0..fields.len())
+        .map(|index| fields.get(&index.to_string()).cloned())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| RuntimeError::invalid_argument("object_to_array", "the keys must be contiguous indices from 0"))?;
+    Ok(Some(Value::Array(items)))
+}
+
+
+pub fn builtin_functions() -> Vec<BuiltInFunction>{
+    vec![
+        BuiltInFunction::new("print".to_string(), 0, true, builtin_print),
+        BuiltInFunction::new("println".to_string(), 0, true, builtin_println),
+        BuiltInFunction::new("eprint".to_string(), 0, true, builtin_eprint),
+        BuiltInFunction::new("eprintln".to_string(), 0, true, builtin_eprintln),
+        BuiltInFunction::new("input".to_string(), 0, false, builtin_input),
+        BuiltInFunction::new("input_print".to_string(), 0, true, builtin_input_print).with_max_args(1),
+        BuiltInFunction::new("len".to_string(), 1, false, builtin_len),
+        BuiltInFunction::new("merge".to_string(), 2, false, builtin_merge),
+        BuiltInFunction::new("compose".to_string(), 2, false, builtin_compose),
+        BuiltInFunction::new("pipe".to_string(), 1, true, builtin_pipe),
+        BuiltInFunction::new("to_json".to_string(), 1, false, builtin_to_json),
+        BuiltInFunction::new("from_json".to_string(), 1, false, builtin_from_json),
+        BuiltInFunction::new("replace".to_string(), 3, false, builtin_replace),
+        BuiltInFunction::new("trim_start".to_string(), 1, false, builtin_trim_start),
+        BuiltInFunction::new("trim_end".to_string(), 1, false, builtin_trim_end),
+        BuiltInFunction::new("starts_with".to_string(), 2, false, builtin_starts_with),
+        BuiltInFunction::new("ends_with".to_string(), 2, false, builtin_ends_with),
+        BuiltInFunction::new("object_from_pairs".to_string(), 2, false, builtin_object_from_pairs),
+        BuiltInFunction::new("unique".to_string(), 1, false, builtin_unique),
+        BuiltInFunction::new("group_by".to_string(), 2, false, builtin_group_by),
+        BuiltInFunction::new("exists".to_string(), 1, false, builtin_exists),
+        BuiltInFunction::new("freeze".to_string(), 1, false, builtin_freeze),
+        BuiltInFunction::new("pad".to_string(), 2, false, builtin_pad),
+        BuiltInFunction::new("fixed".to_string(), 2, false, builtin_fixed),
+        BuiltInFunction::new("abs_diff".to_string(), 2, false, builtin_abs_diff),
+        BuiltInFunction::new("clamp01".to_string(), 1, false, builtin_clamp01),
+        BuiltInFunction::new("lerp".to_string(), 3, false, builtin_lerp),
+        BuiltInFunction::new("from_template".to_string(), 2, false, builtin_from_template),
+        BuiltInFunction::new("filter_map".to_string(), 2, false, builtin_filter_map),
+        BuiltInFunction::new("max_by".to_string(), 2, false, builtin_max_by),
+        BuiltInFunction::new("min_by".to_string(), 2, false, builtin_min_by),
+        BuiltInFunction::new("scan".to_string(), 3, false, builtin_scan),
+        BuiltInFunction::new("rename_field".to_string(), 3, false, builtin_rename_field),
+        BuiltInFunction::new("array_with_capacity".to_string(), 1, false, builtin_array_with_capacity),
+        BuiltInFunction::new("push".to_string(), 2, false, builtin_push),
+        BuiltInFunction::new("coalesce".to_string(), 0, true, builtin_coalesce),
+        BuiltInFunction::new("fill".to_string(), 2, false, builtin_fill),
+        BuiltInFunction::new("resize".to_string(), 3, false, builtin_resize),
+        BuiltInFunction::new("chr".to_string(), 1, false, builtin_chr),
+        BuiltInFunction::new("ord".to_string(), 1, false, builtin_ord),
+        BuiltInFunction::new("find".to_string(), 2, false, builtin_find),
+        BuiltInFunction::new("any".to_string(), 2, false, builtin_any),
+        BuiltInFunction::new("all".to_string(), 2, false, builtin_all),
+        BuiltInFunction::new("entries".to_string(), 1, false, builtin_entries),
+        BuiltInFunction::new("from_entries".to_string(), 1, false, builtin_from_entries),
+        BuiltInFunction::new("str".to_string(), 1, false, builtin_str),
+        BuiltInFunction::new("functions".to_string(), 0, false, builtin_functions_list),
+        BuiltInFunction::new("pass_by_value".to_string(), 1, false, builtin_pass_by_value),
+        BuiltInFunction::new("vm_stats".to_string(), 0, false, builtin_vm_stats),
+        BuiltInFunction::new("nan".to_string(), 0, false, builtin_nan),
+        BuiltInFunction::new("inf".to_string(), 0, false, builtin_inf),
+        BuiltInFunction::new("is_nan".to_string(), 1, false, builtin_is_nan),
+        BuiltInFunction::new("is_finite".to_string(), 1, false, builtin_is_finite),
+        BuiltInFunction::new("format".to_string(), 1, true, builtin_format),
+        BuiltInFunction::new("split_lines".to_string(), 1, false, builtin_split_lines),
+        BuiltInFunction::new("join_lines".to_string(), 1, false, builtin_join_lines),
+        BuiltInFunction::new("deep_equals".to_string(), 2, false, builtin_deep_equals),
+        BuiltInFunction::new("argmin".to_string(), 1, false, builtin_argmin),
+        BuiltInFunction::new("argmax".to_string(), 1, false, builtin_argmax),
+        BuiltInFunction::new("to_byte".to_string(), 1, false, builtin_to_byte),
+        BuiltInFunction::new("byte_array".to_string(), 1, false, builtin_byte_array),
+        BuiltInFunction::new("print_table".to_string(), 1, false, builtin_print_table),
+        BuiltInFunction::new("get".to_string(), 3, false, builtin_get),
+        BuiltInFunction::new("pluck".to_string(), 2, false, builtin_pluck),
+        BuiltInFunction::new("apply".to_string(), 2, false, builtin_apply),
+        BuiltInFunction::new("sleep".to_string(), 1, false, builtin_sleep),
+        BuiltInFunction::new("env".to_string(), 1, true, builtin_env).with_max_args(2),
+        BuiltInFunction::new("args".to_string(), 0, false, builtin_args),
+        BuiltInFunction::new("hash".to_string(), 1, false, builtin_hash),
+        BuiltInFunction::new("float_to_bits".to_string(), 1, false, builtin_float_to_bits),
+        BuiltInFunction::new("bits_to_float".to_string(), 1, false, builtin_bits_to_float),
+        BuiltInFunction::new("partition".to_string(), 2, false, builtin_partition),
+        BuiltInFunction::new("take".to_string(), 2, false, builtin_take),
+        BuiltInFunction::new("drop".to_string(), 2, false, builtin_drop),
+        BuiltInFunction::new("take_while".to_string(), 2, false, builtin_take_while),
+        BuiltInFunction::new("drop_while".to_string(), 2, false, builtin_drop_while),
+        BuiltInFunction::new("chunk".to_string(), 2, false, builtin_chunk),
+        BuiltInFunction::new("windows".to_string(), 2, false, builtin_windows),
+        BuiltInFunction::new("ljust".to_string(), 2, true, builtin_ljust).with_max_args(3),
+        BuiltInFunction::new("rjust".to_string(), 2, true, builtin_rjust).with_max_args(3),
+        BuiltInFunction::new("center".to_string(), 2, true, builtin_center).with_max_args(3),
+        BuiltInFunction::new("int".to_string(), 1, false, builtin_int),
+        BuiltInFunction::new("float".to_string(), 1, false, builtin_float),
+        BuiltInFunction::new("seed".to_string(), 1, false, builtin_seed),
+        BuiltInFunction::new("random".to_string(), 0, false, builtin_random),
+        BuiltInFunction::new("shuffle".to_string(), 1, false, builtin_shuffle),
+        BuiltInFunction::new("choice".to_string(), 1, false, builtin_choice),
+        BuiltInFunction::new("sample".to_string(), 2, false, builtin_sample),
+        BuiltInFunction::new("deep_copy".to_string(), 1, false, builtin_deep_copy),
+        BuiltInFunction::new("frequencies".to_string(), 1, false, builtin_frequencies),
+        BuiltInFunction::new("flatten_object".to_string(), 1, false, builtin_flatten_object),
+        BuiltInFunction::new("assert".to_string(), 1, true, builtin_assert).with_max_args(2),
+        BuiltInFunction::new("assert_eq".to_string(), 2, true, builtin_assert_eq).with_max_args(3),
+        BuiltInFunction::new("array_to_object".to_string(), 1, false, builtin_array_to_object),
+        BuiltInFunction::new("object_to_array".to_string(), 1, false, builtin_object_to_array),
+    ]
+}
+
+impl Value {
+    pub fn as_eval(&mut self) -> Eval {
+        match self {
+            Value::Int(val) => { Eval::Int(*val) }
+            Value::Bool(val) => { Eval::Bool(*val) }
+            Value::Float(val) => { Eval::Float(*val) }
+            Value::String(val) => { Eval::String(val.clone()) }
+            Value::Char(val) => { Eval::Char(*val) }
+            Value::Object(val) => { Eval::Object(Box::new(Eval::Int(*val as i32))) }
+            Value::Function(name) => { Eval::FnRef(name.clone()) }
+            Value::Null => { Eval::Null }
+            Value::Unit => { Eval::Unit }
+            Value::Array(val) => { Eval::Array(val.iter_mut().map(|x| x.as_eval()).collect()) }
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Bool(_) => "bool",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::Char(_) => "char",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+            Value::Function(_) => "function",
+            Value::Null => "null",
+            Value::Unit => "unit",
+        }
+    }
+
+    pub fn as_int(&self) -> i32 {
+        match self {
+            Value::Int(val) => *val,
+            _ => panic!("Expected int")
+        }
+    }
+    pub fn as_bool(&self) -> bool {
+        match self {
+            Value::Bool(val) => *val,
+            _ => panic!("Expected bool")
+        }
+    }
+    pub fn as_float(&self) -> f32 {
+        match self {
+            Value::Float(val) => *val,
+            _ => panic!("Expected float")
+        }
+    }
+    pub fn as_string(&self) -> String {
+        match self {
+            Value::String(val) => val.clone(),
+            _ => panic!("Expected string")
+        }
+    }
+}
+
+/// A value formatted with `{}` the way `print` shows it, made by `VirtualMachine::display`.
+pub struct DisplayValue<'a> {
+    val: &'a Value,
+    precision: Option<usize>,
+}
+
+impl Display for DisplayValue<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&stringify_with(self.val, self.precision))
+    }
+}
+
+/// Orders values of the same type, arrays lexicographically. Values of different types are unordered.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Int(l), Value::Int(r)) => l.partial_cmp(r),
+            (Value::Float(l), Value::Float(r)) => l.partial_cmp(r),
+            (Value::Bool(l), Value::Bool(r)) => l.partial_cmp(r),
+            (Value::String(l), Value::String(r)) => l.partial_cmp(r),
+            (Value::Char(l), Value::Char(r)) => l.partial_cmp(r),
+            (Value::Array(l), Value::Array(r)) => l.partial_cmp(r),
+            _ => None
+        }
+    }
+}
+
+/// The hashable subset of `Value`, for builtins that key a `HashMap` by value.
+/// Floats are compared by bit pattern. Arrays, objects, functions, null and unit are not hashable.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashableValue {
+    Int(i32),
+    Bool(bool),
+    Float(u32),
+    String(String),
+    Char(char),
+}
+
+impl TryFrom<&Value> for HashableValue {
+    type Error = RuntimeError;
+
+    fn try_from(val: &Value) -> Result<Self, Self::Error> {
+        match val {
+            Value::Int(val) => Ok(HashableValue::Int(*val)),
+            Value::Bool(val) => Ok(HashableValue::Bool(*val)),
+            Value::Float(val) => Ok(HashableValue::Float(val.to_bits())),
+            Value::String(val) => Ok(HashableValue::String(val.clone())),
+            Value::Char(val) => Ok(HashableValue::Char(*val)),
+            val => Err(RuntimeError::Unhashable(val.type_name()))
+        }
+    }
+}
+
+impl From<HashableValue> for Value {
+    fn from(val: HashableValue) -> Self {
+        match val {
+            HashableValue::Int(val) => Value::Int(val),
+            HashableValue::Bool(val) => Value::Bool(val),
+            HashableValue::Float(bits) => Value::Float(f32::from_bits(bits)),
+            HashableValue::String(val) => Value::String(val),
+            HashableValue::Char(val) => Value::Char(val),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Object {
+    pub fields: HashMap<String, Value>,
+}
+
+impl Object {
+    fn new(fields: HashMap<String, Value>) -> Self {
+        Object {
+            fields
+        }
+    }
+}
+
+pub enum GcApproach {
+    None,
+    ReferenceCounting,
+    Custom { func: fn(&mut VirtualMachine, Vec<String>) }
+}
+
+impl Debug for GcApproach {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GcApproach::None => { write!(f, "None") }
+            GcApproach::ReferenceCounting => { write!(f, "ReferenceCounting") }
+            GcApproach::Custom { .. } => { write!(f, "Custom") }
+        }
+    }
+}
+
+/// Whether ids of deleted objects are handed out again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdMode {
+    Monotonic,
+    Reuse,
+}
+
+/// Allocates object ids, recycling the ids of deleted objects in `IdMode::Reuse`.
+#[derive(Debug)]
+pub struct FreeList {
+    pub mode: IdMode,
+    next: usize,
+    free: Vec<usize>,
+}
+
+impl FreeList {
+    pub fn new(mode: IdMode) -> Self {
+        FreeList {
+            mode,
+            next: 0,
+            free: vec![],
+        }
+    }
+
+    /// Returns the lowest freed id, or the next new one, skipping ids `taken` reports as in use.
+    pub fn allocate(&mut self, taken: impl Fn(usize) -> bool) -> usize {
+        while let Some(id) = self.free.pop() {
+            if !taken(id) {
+                return id;
+            }
+        }
+
+        while taken(self.next) {
+            self.next += 1;
+        }
+        self.next += 1;
+        self.next - 1
+    }
+
+    pub fn release(&mut self, id: usize) {
+        if self.mode == IdMode::Reuse && !self.free.contains(&id) {
+            // Kept sorted high to low so `pop` hands out the lowest id first.
+            let index = self.free.partition_point(|&free| free > id);
+            self.free.insert(index, id);
+        }
+    }
+}
+
+/// Destination for everything scripts print, stdout unless the host swaps it out.
+pub struct OutputSink(pub Box<dyn Write>);
+
+impl OutputSink {
+    pub fn stderr() -> Self {
+        OutputSink(Box::new(std::io::stderr()))
+    }
+}
+
+impl Default for OutputSink {
+    fn default() -> Self {
+        OutputSink(Box::new(std::io::stdout()))
+    }
+}
+
+impl Write for OutputSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Debug for OutputSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OutputSink")
+    }
+}
+
+/// A point-in-time view of the VM for hosts that want to render its state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmSnapshot {
+    pub globals: Vec<(String, Value)>,
+    pub object_count: usize,
+    pub functions: Vec<String>,
+    pub scope_depth: usize,
+}
+
+/// The outcome of executing one statement of a loaded program with `VirtualMachine::step`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepResult {
+    /// Index of the statement that ran.
+    pub index: usize,
+    pub result: Result<(), RuntimeError>,
+}
+
+/// Tally of the `assert` and `assert_eq` calls made while `collect_assertions` is on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AssertionResults {
+    pub passed: usize,
+    pub failed: usize,
+    /// Messages of the failed assertions, in the order they failed.
+    pub failures: Vec<String>,
+}
+
+
+/// A `break`, `continue` or `return` travelling up from a nested block to its loop or function.
+#[derive(Debug)]
+enum Signal {
+    Break(Value),
+    Continue,
+    Return(Value),
+}
+
+
+#[derive(Debug)]
+pub struct VirtualMachine {
+    pub objects: HashMap<usize, Object>,
+    pub objects_in_use: Vec<(usize, u32)>,
+    pub functions: HashMap<String, Box<dyn Callable>>,
+    pub global_variables: HashMap<String, Value>,
+    pub locals: Vec<HashMap<String, Value>>,
+    pub local: Option<HashMap<String, Value>>,
+    pub gc_approach: GcApproach,
+    pub object_ids: FreeList,
+    pub output: OutputSink,
+    /// Where `eprint` and `eprintln` write, stderr by default.
+    pub error_output: OutputSink,
+    /// Aborts any loop running more passes than this, `None` lets loops run forever.
+    pub max_loop_iterations: Option<usize>,
+    pub deadline: Option<Instant>,
+    /// Aborts evaluating expressions nested deeper than this, before they overflow the stack.
+    /// Each function call counts from zero again. `None`, the default, never aborts.
+    pub max_expression_depth: Option<usize>,
+    expression_depth: usize,
+    /// Name of the variable holding the zero-based pass count inside `Loop` and `WhileLoop` bodies.
+    pub loop_counter_name: String,
+    pub frozen_objects: HashSet<usize>,
+    /// Program arguments the host passed in, returned by `args()`.
+    pub program_args: Vec<String>,
+    /// Stops scripts from reading the host environment, for running untrusted code.
+    pub sandboxed: bool,
+    /// Makes `SetMember` fail on fields the object does not have yet, instead of adding them.
+    pub strict_members: bool,
+    /// Records failed assertions in `assertion_results` and carries on instead of erroring.
+    pub collect_assertions: bool,
+    assertion_results: AssertionResults,
+    /// The program loaded for stepping, with the index of the next statement to run.
+    pub program: Vec<Node>,
+    pub instruction_pointer: usize,
+    /// Statement indices of the loaded program that `run_until_breakpoint` pauses before.
+    pub breakpoints: HashSet<usize>,
+    paused_at: Option<usize>,
+    signal: Option<Signal>,
+    /// Kind of the statement `single_run` is executing, reported by `panic_ctx`.
+    executing: Option<&'static str>,
+    closure_count: usize,
+    /// Closures and composed functions the VM created, with the order they were created in.
+    /// `collect_functions` removes the ones no value refers to any more.
+    generated_functions: HashMap<String, usize>,
+    generated_count: usize,
+    /// `generated_count` when each running function was entered. Functions generated before the
+    /// innermost entry may still be held by a caller mid-expression, so they are never collected.
+    frame_watermarks: Vec<usize>,
+    /// Values a running statement holds outside any variable, like the rest of a `For` array.
+    pinned: Vec<Value>,
+    next_function_collection: usize,
+    /// Kinds of the most recently executed statements, once `enable_execution_log` is called.
+    execution_log: Option<VecDeque<&'static str>>,
+    execution_log_cap: usize,
+    /// Decimals `print` and `str` show for floats, `None` shows the shortest round-trip form.
+    pub float_precision: Option<usize>,
+    /// State of the splitmix64 generator behind `random`, `shuffle` and friends.
+    rng_state: u64,
+}
+
+impl VirtualMachine {
+    pub fn new(gc_approach: GcApproach) -> Self {
+        let mut functions = HashMap::new();
+
+        for func in builtin_functions() {
+            functions.insert(func.name.clone(), Box::new(func) as Box<dyn Callable>);
+        }
+
+        VirtualMachine {
+            objects: HashMap::new(),
+            objects_in_use: vec![],
+            functions,
+            global_variables: Default::default(),
+            locals: vec![],
+            local: Default::default(),
+            gc_approach,
+            object_ids: FreeList::new(IdMode::Monotonic),
+            output: OutputSink::default(),
+            error_output: OutputSink::stderr(),
+            max_loop_iterations: None,
+            deadline: None,
+            max_expression_depth: None,
+            expression_depth: 0,
+            loop_counter_name: DEFAULT_LOOP_COUNTER_NAME.to_string(),
+            frozen_objects: HashSet::new(),
+            strict_members: false,
+            collect_assertions: false,
+            assertion_results: AssertionResults::default(),
+            sandboxed: false,
+            program_args: vec![],
+            program: vec![],
+            instruction_pointer: 0,
+            breakpoints: HashSet::new(),
+            paused_at: None,
+            signal: None,
+            executing: None,
+            closure_count: 0,
+            generated_functions: HashMap::new(),
+            generated_count: 0,
+            frame_watermarks: vec![],
+            pinned: vec![],
+            next_function_collection: MIN_FUNCTION_COLLECTION,
+            execution_log: None,
+            execution_log_cap: 0,
+            float_precision: None,
+            rng_state: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64),
+        }
+    }
+
+    pub fn set_output(&mut self, writer: impl Write + 'static) {
+        self.output = OutputSink(Box::new(writer));
+    }
+
+    pub fn set_error_output(&mut self, writer: impl Write + 'static) {
+        self.error_output = OutputSink(Box::new(writer));
+    }
+
+    /// Starts recording the kind of every statement run, keeping only the latest `cap` entries.
+    pub fn enable_execution_log(&mut self, cap: usize) {
+        self.execution_log = Some(VecDeque::with_capacity(cap));
+        self.execution_log_cap = cap;
+    }
+
+    /// The statements recorded since `enable_execution_log`, oldest first, empty if it was never called.
+    pub fn execution_log(&self) -> Vec<&'static str> {
+        self.execution_log.iter().flatten().copied().collect()
+    }
+
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.program_args = args;
+    }
+
+    /// The assertions tallied while `collect_assertions` was on.
+    pub fn assertion_results(&self) -> &AssertionResults {
+        &self.assertion_results
+    }
+
+    /// Counts a passing assertion, and collects or returns a failing one.
+    fn record_assertion(&mut self, passed: bool, message: String) -> Result<(), RuntimeError> {
+        if passed {
+            self.assertion_results.passed += 1;
+        } else if self.collect_assertions {
+            self.assertion_results.failed += 1;
+            self.assertion_results.failures.push(message);
+        } else {
+            return Err(RuntimeError::AssertionFailed(message));
+        }
+        Ok(())
+    }
+
+    /// Reseeds the PRNG, so the same seed always yields the same sequence of random values.
+    /// A new VM is seeded from the clock.
+    pub fn seed_random(&mut self, seed: u64) {
+        self.rng_state = seed;
+    }
+
+    pub fn next_random(&mut self) -> u64 {
+        self.rng_state = self.rng_state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A random index in `0..bound`, `bound` must be positive.
+    pub fn random_below(&mut self, bound: usize) -> usize {
+        (self.next_random() % bound as u64) as usize
+    }
+
+    pub fn add_defined_functions(&mut self, functions: Vec<DefinedFunction>) {
+        for func in functions {
+            self.functions.insert(func.name.clone(), Box::new(func) as Box<dyn Callable>);
+        }
+    }
+
+    pub fn add_rust_functions(&mut self, functions: Vec<BuiltInFunction>) {
+        for func in functions {
+            self.functions.insert(func.name.clone(), Box::new(func) as Box<dyn Callable>);
+        }
+    }
+
+    /// Registers the top-level `FnDef`s of a program without running any of its other statements,
+    /// so a library program can provide functions to the one that runs.
+    pub fn load_functions_from(&mut self, nodes: &[Node]) {
+        let functions = nodes.iter()
+            .filter_map(|node| match node {
+                Node::FnDef(name, args, body) => Some(DefinedFunction::new(name.clone(), args.clone(), body.clone(), false)),
+                _ => None
+            })
+            .collect();
+        self.add_defined_functions(functions);
+    }
+
+    /// Registers functions under `prefix.name`, so modules can share function names.
+    pub fn register_module(&mut self, prefix: &str, functions: Vec<BuiltInFunction>) {
+        for mut func in functions {
+            func.name = format!("{}.{}", prefix, func.name);
+            self.functions.insert(func.name.clone(), Box::new(func) as Box<dyn Callable>);
+        }
+    }
+
+    /// Captures globals and function names sorted by name, so snapshots compare reliably.
+    pub fn snapshot(&self) -> VmSnapshot {
+        let mut globals: Vec<(String, Value)> = self.global_variables.iter()
+            .map(|(name, val)| (name.clone(), val.clone()))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut functions: Vec<String> = self.functions.keys().cloned().collect();
+        functions.sort();
+
+        VmSnapshot {
+            globals,
+            object_count: self.objects.len(),
+            functions,
+            scope_depth: self.locals.len(),
+        }
+    }
+
+    /// Runs `f` in a fresh local scope, putting the caller's scope back once it returns.
+    /// Builtins use this to call back into script code without touching `local` and `locals` by hand.
+    pub fn with_new_scope<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let pushed = match self.local.take() {
+            Some(local) => {
+                self.locals.push(local);
+                true
+            }
+            None => false
+        };
+
+        self.local = Some(HashMap::new());
+        let expression_depth = std::mem::take(&mut self.expression_depth);
+        self.frame_watermarks.push(self.generated_count);
+        let res = f(self);
+        self.frame_watermarks.pop();
+        self.expression_depth = expression_depth;
+        for val in self.local.take().into_iter().flat_map(HashMap::into_values) {
+            self.dec_use_count(&val);
+        }
+        self.local = if pushed { self.locals.pop() } else { None };
+        res
+    }
+
+    /// Registers a function the program created at runtime, to be removed again by
+    /// `collect_functions` once no value refers to it. A handle only the host holds does not
+    /// count, so store it in a global variable to keep the function around.
+    pub fn add_generated_function(&mut self, name: String, function: Box<dyn Callable>) -> Value {
+        self.generated_count += 1;
+        self.generated_functions.insert(name.clone(), self.generated_count);
+        self.functions.insert(name.clone(), function);
+        Value::Function(name)
+    }
+
+    /// Runs `f` with `val` counted as in use, for values a statement holds outside any variable.
+    fn with_pinned<R>(&mut self, val: Value, f: impl FnOnce(&mut Self) -> R) -> R {
+        self.pinned.push(val);
+        let res = f(self);
+        self.pinned.pop();
+        res
+    }
+
+    /// Removes generated functions that no variable, object field, pending value or live function
+    /// refers to. Only runs once enough have piled up since the last collection.
+    fn collect_functions(&mut self) {
+        if self.generated_functions.len() < self.next_function_collection {
+            return;
+        }
+
+        let watermark = self.frame_watermarks.last().copied().unwrap_or(0);
+        let mut pending: Vec<Value> = self.global_variables.values()
+            .chain(self.local.iter().flat_map(HashMap::values))
+            .chain(self.locals.iter().flat_map(HashMap::values))
+            .chain(self.objects.values().flat_map(|obj| obj.fields.values()))
+            .chain(self.pinned.iter())
+            .cloned()
+            .collect();
+        if let Some(Signal::Break(val) | Signal::Return(val)) = &self.signal {
+            pending.push(val.clone());
+        }
+        pending.extend(self.generated_functions.iter()
+            .filter(|(_, &created)| created <= watermark)
+            .map(|(name, _)| Value::Function(name.clone())));
+
+        let mut reachable = HashSet::new();
+        while let Some(val) = pending.pop() {
+            match val {
+                Value::Function(name) => {
+                    if let Some(function) = self.functions.get(&name) {
+                        if reachable.insert(name) {
+                            pending.extend(function.held_values());
+                        }
+                    }
+                }
+                Value::Array(items) => pending.extend(items),
+                _ => {}
+            }
+        }
+
+        // A generated function that is running is out of `functions` and kept until it returns.
+        let unused: Vec<String> = self.generated_functions.keys()
+            .filter(|name| !reachable.contains(*name) && self.functions.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in unused {
+            self.generated_functions.remove(&name);
+            self.functions.remove(&name);
+        }
+        self.next_function_collection = MIN_FUNCTION_COLLECTION.max(self.generated_functions.len() * 2);
+    }
+
+    /// Formats a value the way `print` shows it, honouring `float_precision`.
+    pub fn stringify(&self, val: &Value) -> String {
+        stringify_with(val, self.float_precision)
+    }
+
+    /// Wraps a value for `{}` formatting, honouring `float_precision` like `stringify`.
+    pub fn display<'a>(&self, val: &'a Value) -> DisplayValue<'a> {
+        DisplayValue { val, precision: self.float_precision }
+    }
+
+    /// Iterates over every live object along with its id, without cloning any fields.
+    pub fn iter_objects(&self) -> impl Iterator<Item = (usize, &Object)> {
+        self.objects.iter().map(|(id, obj)| (*id, obj))
+    }
+
+    pub fn expect_int(&mut self, arg: Eval, function: &str, index: usize) -> Result<i32, RuntimeError> {
+        match self.eval(arg)? {
+            Value::Int(val) => Ok(val),
+            val => Err(RuntimeError::argument_type(function, index, "int", &val))
+        }
+    }
+
+    pub fn expect_bool(&mut self, arg: Eval, function: &str, index: usize) -> Result<bool, RuntimeError> {
+        match self.eval(arg)? {
+            Value::Bool(val) => Ok(val),
+            val => Err(RuntimeError::argument_type(function, index, "bool", &val))
+        }
+    }
+
+    pub fn expect_float(&mut self, arg: Eval, function: &str, index: usize) -> Result<f32, RuntimeError> {
+        match self.eval(arg)? {
+            Value::Float(val) => Ok(val),
+            val => Err(RuntimeError::argument_type(function, index, "float", &val))
+        }
+    }
+
+    /// Accepts an int or a float, coercing it to a float.
+    pub fn expect_number(&mut self, arg: Eval, function: &str, index: usize) -> Result<f32, RuntimeError> {
+        coerce_number(self.eval(arg)?, function, index)
+    }
+
+    pub fn expect_string(&mut self, arg: Eval, function: &str, index: usize) -> Result<String, RuntimeError> {
+        match self.eval(arg)? {
+            Value::String(val) => Ok(val),
+            val => Err(RuntimeError::argument_type(function, index, "string", &val))
+        }
+    }
+
+    pub fn expect_array(&mut self, arg: Eval, function: &str, index: usize) -> Result<Vec<Value>, RuntimeError> {
+        match self.eval(arg)? {
+            Value::Array(val) => Ok(val),
+            val => Err(RuntimeError::argument_type(function, index, "array", &val))
+        }
+    }
+
+    pub fn expect_function(&mut self, arg: Eval, function: &str, index: usize) -> Result<String, RuntimeError> {
+        match self.eval(arg)? {
+            Value::Function(name) => Ok(name),
+            val => Err(RuntimeError::argument_type(function, index, "function", &val))
+        }
+    }
+
+    pub fn expect_object(&mut self, arg: Eval, function: &str, index: usize) -> Result<usize, RuntimeError> {
+        match self.eval(arg)? {
+            Value::Object(id) => Ok(id),
+            val => Err(RuntimeError::argument_type(function, index, "object", &val))
+        }
+    }
+
+    /// Stores a new object under the next free id and tracks the use of its fields.
+    pub fn allocate_object(&mut self, fields: HashMap<String, Value>) -> usize {
+        let objects = &self.objects;
+        let id = self.object_ids.allocate(|id| objects.contains_key(&id));
+
+        for val in fields.values() {
+            self.inc_use_count(val);
+        }
+        self.objects.insert(id, Object::new(fields));
+        id
+    }
+
+    /// Copies a value, giving every object reachable from it a fresh copy.
+    /// Objects reached more than once, including through cycles, are copied only once.
+    pub fn copy_value(&mut self, val: &Value, copies: &mut HashMap<usize, usize>) -> Value {
+        match val {
+            Value::Object(id) => {
+                if let Some(copy) = copies.get(id) {
+                    return Value::Object(*copy);
+                }
+
+                let copy = self.allocate_object(HashMap::new());
+                copies.insert(*id, copy);
+
+                let fields = self.objects[id].fields.clone();
+                let fields: HashMap<String, Value> = fields.iter()
+                    .map(|(name, val)| (name.clone(), self.copy_value(val, copies)))
+                    .collect();
+                for val in fields.values() {
+                    self.inc_use_count(val);
+                }
+                self.objects.get_mut(&copy).unwrap().fields = fields;
+                Value::Object(copy)
+            }
+            Value::Array(items) => {
+                Value::Array(items.iter().map(|item| self.copy_value(item, copies)).collect())
+            }
+            val => val.clone()
+        }
+    }
+
+    /// Compares two values structurally, following object ids into their fields.
+    /// A pair of objects already being compared further up counts as equal, so cycles terminate.
+    pub fn deep_equals(&self, lhs: &Value, rhs: &Value, comparing: &mut HashSet<(usize, usize)>) -> bool {
+        match (lhs, rhs) {
+            (Value::Object(l), Value::Object(r)) => {
+                if l == r || !comparing.insert((*l, *r)) {
+                    return true;
+                }
+
+                let (l, r) = (&self.objects[l].fields, &self.objects[r].fields);
+                l.len() == r.len() && l.iter().all(|(name, val)| {
+                    r.get(name).is_some_and(|other| self.deep_equals(val, other, comparing))
+                })
+            }
+            (Value::Array(l), Value::Array(r)) => {
+                l.len() == r.len() && l.iter().zip(r).all(|(l, r)| self.deep_equals(l, r, comparing))
+            }
+            (lhs, rhs) => lhs == rhs
+        }
+    }
+
+    /// Feeds a value to the hasher, objects by their fields in name order rather than by id.
+    /// An object reached again while it is still being hashed only adds a marker.
+    fn hash_value(&self, val: &Value, hasher: &mut StableHasher, visiting: &mut Vec<usize>) {
+        match val {
+            Value::Int(val) => hasher.write_tagged(0, &val.to_le_bytes()),
+            Value::Bool(val) => hasher.write_tagged(1, &[*val as u8]),
+            Value::Float(val) => hasher.write_tagged(2, &val.to_bits().to_le_bytes()),
+            Value::String(val) => hasher.write_tagged(3, val.as_bytes()),
+            Value::Char(val) => hasher.write_tagged(4, &(*val as u32).to_le_bytes()),
+            Value::Function(name) => hasher.write_tagged(5, name.as_bytes()),
+            Value::Null => hasher.write_tagged(6, &[]),
+            Value::Unit => hasher.write_tagged(7, &[]),
+            Value::Array(items) => {
+                hasher.write_tagged(8, &(items.len() as u64).to_le_bytes());
+                for item in items {
+                    self.hash_value(item, hasher, visiting);
+                }
+            }
+            Value::Object(id) => {
+                if visiting.contains(id) {
+                    hasher.write_tagged(10, &[]);
+                    return;
+                }
+                visiting.push(*id);
+
+                let mut fields: Vec<(&String, &Value)> = self.objects[id].fields.iter().collect();
+                fields.sort_by(|a, b| a.0.cmp(b.0));
+                hasher.write_tagged(9, &(fields.len() as u64).to_le_bytes());
+                for (name, val) in fields {
+                    hasher.write_tagged(3, name.as_bytes());
+                    self.hash_value(val, hasher, visiting);
+                }
+                visiting.pop();
+            }
+        }
+    }
+
+    /// Resolves an object location: a literal id, an object value, or the name of a variable holding one.
+    fn resolve_object(&self, obj_loc: Value) -> usize {
+        match obj_loc {
+            Value::Int(id) => id as usize,
+            Value::Object(id) => id,
+            Value::String(var_name) => {
+                match self.lookup_variable(&var_name) {
+                    Some(Value::Object(id)) => *id,
+                    Some(val) => panic!("{}", self.panic_ctx(format!("Variable {} holds {}, not an object", var_name, val.type_name()))),
+                    None => panic!("{}", self.panic_ctx(format!("Variable {} does not exist", var_name)))
+                }
+            }
+            val => panic!("{}", self.panic_ctx(format!("Cannot use {} as an object", val.type_name())))
+        }
+    }
+
+    /// Panics for an operator applied to operands it has no meaning for.
+    fn unsupported_operands(&self, operator: &str, lhs: Eval, rhs: Eval) -> ! {
+        panic!("{}", self.panic_ctx(format!("Cannot apply {} to {:?} and {:?}", operator, lhs, rhs)))
+    }
+
+    fn lookup_variable(&self, name: &str) -> Option<&Value> {
+        self.local.as_ref()
+            .and_then(|local| local.get(name))
+            .or_else(|| self.global_variables.get(name))
+    }
+
+    fn lookup_variable_mut(&mut self, name: &str) -> Option<&mut Value> {
+        match self.local.as_mut().and_then(|local| local.get_mut(name)) {
+            Some(val) => Some(val),
+            None => self.global_variables.get_mut(name)
         }
     }
-}
 
+    /// Builds an array value directly from host data, skipping the `Eval::Array` round trip through `eval`.
+    pub fn make_array(&self, items: Vec<Value>) -> Value {
+        Value::Array(items)
+    }
 
-#[derive(Debug)]
-pub struct VirtualMachine {
-    pub objects: HashMap<usize, Object>,
-    pub objects_in_use: Vec<(usize, u32)>,
-    pub functions: HashMap<String, Box<dyn Callable>>,
-    pub global_variables: HashMap<String, Value>,
-    pub locals: Vec<HashMap<String, Value>>,
-    pub local: Option<HashMap<String, Value>>,
-    pub gc_approach: GcApproach,
-}
+    /// Calls a function by name, falling back to a variable holding a function value.
+    pub fn call_function(&mut self, name: String, args: Vec<Eval>) -> Result<Option<Value>, RuntimeError> {
+        let name = match self.lookup_variable(&name) {
+            Some(Value::Function(target)) if !self.functions.contains_key(&name) => target.clone(),
+            _ => name
+        };
 
-impl VirtualMachine {
-    pub fn new(gc_approach: GcApproach) -> Self {
-        let mut functions = HashMap::new();
+        if !self.functions.contains_key(&*name){
+            panic!("{}", self.panic_ctx(format!("Function {} does not exist", name)));
+        }
 
-        for func in builtin_functions() {
-            functions.insert(func.name.clone(), Box::new(func) as Box<dyn Callable>);
+        if let Some(max) = self.functions[&name].max_args_len() {
+            if args.len() > max {
+                return Err(RuntimeError::TooManyArguments { function: name, max, found: args.len() });
+            }
         }
 
-        VirtualMachine {
-            objects: HashMap::new(),
-            objects_in_use: vec![],
-            functions,
-            global_variables: Default::default(),
-            locals: vec![],
-            local: Default::default(),
-            gc_approach,
+        let function = self.functions.remove(&*name).unwrap();
+
+        if function.args_len() != args.len() && !function.is_variadic(){
+            let msg = format!("Function {} takes {} arguments, {} given", name, function.args_len(), args.len());
+            panic!("{}", self.panic_ctx(msg));
         }
+
+        let res = function.call(self, args);
+        self.functions.insert(name, function);
+        res
     }
 
-    pub fn add_defined_functions(&mut self, functions: Vec<DefinedFunction>) {
-        for func in functions {
-            self.functions.insert(func.name.clone(), Box::new(func) as Box<dyn Callable>);
-        }
+    /// A registered function by name. Functions are taken out of the map while they run, so one
+    /// looking itself up from inside its own call gets `FunctionUnavailable` too.
+    pub fn get_function(&self, name: &str) -> Result<&dyn Callable, RuntimeError> {
+        self.functions.get(name)
+            .map(|function| function.as_ref())
+            .ok_or_else(|| RuntimeError::FunctionUnavailable(name.to_string()))
     }
 
-    pub fn add_rust_functions(&mut self, functions: Vec<BuiltInFunction>) {
-        for func in functions {
-            self.functions.insert(func.name.clone(), Box::new(func) as Box<dyn Callable>);
+    /// Calls a function with already evaluated arguments, for builtins taking functions as values.
+    pub fn call_with_values(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let args = args.into_iter().map(|mut arg| arg.as_eval()).collect();
+        Ok(self.call_function(name.to_string(), args)?.unwrap_or(Value::Unit))
+    }
+
+    /// Evaluates an operand in the current scope, leaving a literal for the operator arms to match on.
+    fn eval_operand(&mut self, operand: Eval) -> Result<Eval, RuntimeError> {
+        Ok(self.eval(operand)?.as_eval())
+    }
+
+    /// Converts a value for `Eval::Cast` and the `int` and `float` builtins. Floats truncate toward
+    /// zero, strings are parsed after trimming and must give a finite number, bools become 0 or 1,
+    /// and numbers are true when non-zero.
+    fn cast(&self, val: Value, to: TypeName) -> Result<Value, RuntimeError> {
+        let from = val.type_name();
+        let invalid = || RuntimeError::InvalidCast { from, to: to.name() };
+
+        let res = match (to, val) {
+            (TypeName::String, val) => Value::String(self.stringify(&val)),
+            (TypeName::Int, Value::Int(val)) => Value::Int(val),
+            (TypeName::Int, Value::Float(val)) => {
+                if !val.is_finite() || val < i32::MIN as f32 || val >= i32::MAX as f32 {
+                    return Err(invalid());
+                }
+                Value::Int(val as i32)
+            }
+            (TypeName::Int, Value::Bool(val)) => Value::Int(val as i32),
+            (TypeName::Int, Value::Char(val)) => Value::Int(val as i32),
+            (TypeName::Int, Value::String(val)) => Value::Int(val.trim().parse().map_err(|_| invalid())?),
+            (TypeName::Float, Value::Int(val)) => Value::Float(val as f32),
+            (TypeName::Float, Value::Float(val)) => Value::Float(val),
+            (TypeName::Float, Value::Bool(val)) => Value::Float(val as i32 as f32),
+            (TypeName::Float, Value::String(val)) => {
+                // `parse` also takes "inf" and "nan", and overflows huge exponents to infinity.
+                let val: f32 = val.trim().parse().map_err(|_| invalid())?;
+                if !val.is_finite() {
+                    return Err(invalid());
+                }
+                Value::Float(val)
+            }
+            (TypeName::Bool, Value::Bool(val)) => Value::Bool(val),
+            (TypeName::Bool, Value::Int(val)) => Value::Bool(val != 0),
+            (TypeName::Bool, Value::Float(val)) => Value::Bool(val != 0.0),
+            (TypeName::Bool, Value::String(val)) => match val.trim() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => return Err(invalid())
+            },
+            _ => return Err(invalid())
+        };
+        Ok(res)
+    }
+
+    pub fn eval(&mut self, val: Eval) -> Result<Value, RuntimeError> {
+        if let Some(max) = self.max_expression_depth {
+            if self.expression_depth >= max {
+                return Err(RuntimeError::ExpressionTooDeep(max));
+            }
         }
+
+        self.expression_depth += 1;
+        let res = self.eval_expression(val);
+        self.expression_depth -= 1;
+        res
     }
 
-    pub fn eval(&mut self, val: Eval) -> Value {
-        match val {
+    fn eval_expression(&mut self, val: Eval) -> Result<Value, RuntimeError> {
+        let res = match val {
             Eval::Int(i) => { Value::Int(i) }
             Eval::Bool(b) => { Value::Bool(b) }
             Eval::Float(f) => { Value::Float(f) }
             Eval::String(s) => { Value::String(s) }
-            Eval::Array(arr) => { Value::Array(arr.into_iter().map(|x| self.eval(x)).collect()) }
+            Eval::Char(c) => { Value::Char(c) }
+            Eval::Null => { Value::Null }
+            Eval::Unit => { Value::Unit }
+            Eval::Array(arr) => { Value::Array(arr.into_iter().map(|x| self.eval(x)).collect::<Result<_, _>>()?) }
             Eval::Object(obj) => {
-                let obj_id;
                 match *obj {
-                    Eval::Int(id) => { obj_id = id as usize; }
-                    _ => { unimplemented!() }
+                    Eval::Int(id) => { Value::Object(id as usize) }
+                    other => { panic!("{}", self.panic_ctx(format!("Object id must be an int, found {:?}", other))); }
                 }
-                Value::Object(obj_id)
             }
             Eval::VarRef(name) => {
-                // old
-                // self.global_variables.get(&name).unwrap().clone()
-
-                // new
-                if self.local.is_some(){
-                    return if let Some(val) = self.local.as_ref().unwrap().get(&name) {
-                        val.clone()
-                    } else {
-                        self.global_variables.get(&name).unwrap().clone()
-                    }
-                } else {
-                    self.global_variables.get(&name).unwrap().clone()
+                match self.lookup_variable(&name) {
+                    Some(val) => val.clone(),
+                    None => panic!("{}", self.panic_ctx(format!("Variable {} does not exist", name)))
                 }
             }
             Eval::FnCall(func_name, args) => {
-                if !self.functions.contains_key(&*func_name){
-                    panic!("Function {} does not exist", func_name);
-                }
-
-                let function = self.functions.remove(&*func_name).unwrap();
-
-                if function.args_len() != args.len() && !function.is_variadic(){
-                    panic!("Function {} takes {} arguments, {} given", func_name, function.args_len(), args.len());
+                self.call_function(func_name, args)?.unwrap_or(Value::Unit)
+            }
+            Eval::LoopExpr(body) => {
+                self.with_loop_counter(|vm| vm.loop_run(body))?
+            }
+            Eval::Closure(args, body) => {
+                self.closure_count += 1;
+                let name = format!("<closure {}>", self.closure_count);
+                let captured = self.local.clone().unwrap_or_default();
+                let function = DefinedFunction::new(name.clone(), args, body, false);
+                self.add_generated_function(name, Box::new(ClosureFunction::new(function, captured)))
+            }
+            Eval::Cast(val, to) => {
+                let val = self.eval(*val)?;
+                self.cast(val, to)?
+            }
+            Eval::Assign(var_name, var_val) => {
+                let val = self.eval(*var_val)?;
+                self.assign_variable(var_name, val.clone());
+                val
+            }
+            Eval::FnRef(name) => {
+                if !self.functions.contains_key(&*name){
+                    panic!("{}", self.panic_ctx(format!("Function {} does not exist", name)));
                 }
-
-                let res = match function.call(self, args){
-                    None => { panic!("Function {} returned None", func_name) }
-                    Some(val) => { val }
-                };
-
-                self.functions.insert(func_name, function);
-                res
+                Value::Function(name)
             }
-            Eval::Add(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Add(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Int(l + r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Float(l + r) }
                     (Eval::String(l), Eval::String(r)) => { Value::String(l + &r) }
-                    res => { unimplemented!("{:?}", res) }
+                    (Eval::Char(l), Eval::Int(r)) => { Value::Char(shift_char(l, r)?) }
+                    (l, r) => { self.unsupported_operands("+", l, r) }
                 }
             }
-            Eval::Sub(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Sub(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Int(l - r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Float(l - r) }
-                    _ => { unimplemented!() }
+                    (Eval::Char(l), Eval::Int(r)) => { Value::Char(shift_char(l, -(r as i64))?) }
+                    (Eval::Char(l), Eval::Char(r)) => { Value::Int(l as i32 - r as i32) }
+                    (l, r) => { self.unsupported_operands("-", l, r) }
                 }
             }
-            Eval::Mul(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Mul(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Int(l * r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Float(l * r) }
-                    _ => { unimplemented!() }
+                    (Eval::String(l), Eval::Int(r)) => { Value::String(l.repeat(repeat_count(r)?)) }
+                    (Eval::Array(l), Eval::Int(r)) => {
+                        let count = repeat_count(r)?;
+                        let items: Vec<Value> = l.into_iter().map(|x| self.eval(x)).collect::<Result<_, _>>()?;
+                        Value::Array(std::iter::repeat_n(items, count).flatten().collect())
+                    }
+                    (l, r) => { self.unsupported_operands("*", l, r) }
                 }
             }
-            Eval::Div(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Div(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Int(l / r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Float(l / r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands("/", l, r) }
                 }
             }
-            Eval::Mod(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Mod(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Int(l % r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Float(l % r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands("%", l, r) }
                 }
             }
-            Eval::Pow(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Pow(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Int(l.pow(r as u32)) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Float(l.powf(r)) }
-                    _ => { unimplemented!() }
-                }
-            }
-            Eval::Eq(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
-
-                match (*lhs, *rhs) {
-                    (Eval::Int(l), Eval::Int(r)) => { Value::Bool(l == r) }
-                    (Eval::Float(l), Eval::Float(r)) => { Value::Bool(l == r) }
-                    (Eval::String(l), Eval::String(r)) => { Value::Bool(l == r) }
-                    _ => { unimplemented!() }
-                }
-            }
-            Eval::Ne(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
-
-                match (*lhs, *rhs) {
-                    (Eval::Int(l), Eval::Int(r)) => { Value::Bool(l != r) }
-                    (Eval::Float(l), Eval::Float(r)) => { Value::Bool(l != r) }
-                    (Eval::String(l), Eval::String(r)) => { Value::Bool(l != r) }
-                    _ => { unimplemented!() }
-                }
-            }
-            Eval::Gt(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
-
-                match (*lhs, *rhs) {
+                    (l, r) => { self.unsupported_operands("**", l, r) }
+                }
+            }
+            // Values of different types are never equal, objects compare by id and functions by name.
+            // Floats use IEEE comparison, so NaN is not equal to anything, itself included.
+            Eval::Eq(lhs, rhs) => {
+                let lhs = self.eval(*lhs)?;
+                let rhs = self.eval(*rhs)?;
+                Value::Bool(lhs == rhs)
+            }
+            Eval::Ne(lhs, rhs) => {
+                let lhs = self.eval(*lhs)?;
+                let rhs = self.eval(*rhs)?;
+                Value::Bool(lhs != rhs)
+            }
+            Eval::Gt(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
+
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Bool(l > r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Bool(l > r) }
                     (Eval::String(l), Eval::String(r)) => { Value::Bool(l > r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands(">", l, r) }
                 }
             }
-            Eval::Lt(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Lt(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Bool(l < r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Bool(l < r) }
                     (Eval::String(l), Eval::String(r)) => { Value::Bool(l < r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands("<", l, r) }
                 }
             }
-            Eval::Ge(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Ge(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Bool(l >= r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Bool(l >= r) }
                     (Eval::String(l), Eval::String(r)) => { Value::Bool(l >= r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands(">=", l, r) }
                 }
             }
-            Eval::Le(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Le(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Int(l), Eval::Int(r)) => { Value::Bool(l <= r) }
                     (Eval::Float(l), Eval::Float(r)) => { Value::Bool(l <= r) }
                     (Eval::String(l), Eval::String(r)) => { Value::Bool(l <= r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands("<=", l, r) }
                 }
             }
-            Eval::And(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::And(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Bool(l), Eval::Bool(r)) => { Value::Bool(l && r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands("&&", l, r) }
                 }
             }
-            Eval::Or(mut lhs, mut rhs) => {
-                lhs.deref_var_ref(&mut self.global_variables);
-                rhs.deref_var_ref(&mut self.global_variables);
-                lhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                rhs.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if lhs.is_an_operator(){ lhs = Box::new(self.eval(*lhs).as_eval()); }
-                if rhs.is_an_operator(){ rhs = Box::new(self.eval(*rhs).as_eval()); }
+            Eval::Or(lhs, rhs) => {
+                let lhs = self.eval_operand(*lhs)?;
+                let rhs = self.eval_operand(*rhs)?;
 
-                match (*lhs, *rhs) {
+                match (lhs, rhs) {
                     (Eval::Bool(l), Eval::Bool(r)) => { Value::Bool(l || r) }
-                    _ => { unimplemented!() }
+                    (l, r) => { self.unsupported_operands("||", l, r) }
                 }
             }
-            Eval::Not(mut val) => {
-                val.deref_var_ref(&mut self.global_variables);
-                val.deref_object_member(&mut self.objects, &mut self.global_variables);
-                if val.is_an_operator(){ val = Box::new(self.eval(*val).as_eval()); }
-
-                match *val {
+            Eval::Not(val) => {
+                match self.eval_operand(*val)? {
                     Eval::Bool(b) => { Value::Bool(!b) }
-                    _ => { unimplemented!() }
+                    other => { panic!("{}", self.panic_ctx(format!("Cannot apply ! to {:?}", other))) }
                 }
             }
             Eval::GetMember(obj_id, member) => {
-                let obj_loc = self.eval(*obj_id);
-                let obj_id;
-                match obj_loc {
-                    Value::Int(id) => { obj_id = id as usize; }
-                    Value::String(var_name) => {
-                        match *self.global_variables.get(&var_name).unwrap() {
-                            Value::Object(id) => { obj_id = id as usize; }
-                            _ => { unreachable!()}
-                        }
-                    }
-                    _ => { unreachable!() }
+                let obj_loc = self.eval(*obj_id)?;
+                let obj_id = self.resolve_object(obj_loc);
+                match self.objects.get(&obj_id).map(|obj| obj.fields.get(&member)) {
+                    Some(Some(val)) => val.clone(),
+                    Some(None) => panic!("{}", self.panic_ctx(format!("Object {} has no field {}", obj_id, member))),
+                    None => panic!("{}", self.panic_ctx(format!("Object {} does not exist", obj_id)))
                 }
-                let obj = self.objects.get(&(obj_id as usize)).unwrap();
-                return obj.fields.get(&member).unwrap().clone()
             }
-        }
+        };
+        Ok(res)
     }
 
     fn reference_count(&mut self, variable_name: String){
@@ -612,6 +2742,7 @@ impl VirtualMachine {
                         tracker.1 -= 1;
                         if tracker.1 == 0 {
                             self.objects.remove(&id);
+                            self.object_ids.release(id);
                             self.objects_in_use.remove(i);
                         }
 
@@ -633,7 +2764,9 @@ impl VirtualMachine {
 
         let to_remove: Vec<usize> = self.objects_in_use.iter().filter(|(_, tracker)| *tracker == 0).map(|(id, _)| *id).collect();
         for id in to_remove {
-            self.objects.remove(&id);
+            if self.objects.remove(&id).is_some() {
+                self.object_ids.release(id);
+            }
         }
     }
 
@@ -649,21 +2782,46 @@ impl VirtualMachine {
         }
     }
 
+    /// Drops a use of every object in the value, leaving the ones nothing uses for `run_gc` to
+    /// collect. Objects that were never tracked, like ones the host put in a variable, are skipped.
     fn dec_use_count(&mut self, val: &Value){
         match val {
             Value::Object(id) => {
-                match self.objects_in_use.binary_search_by_key(&id, |(a, _)| a) {
-                    Ok(i) => {
-                        let tracker = self.objects_in_use.get_mut(i).unwrap();
-                        tracker.1 -= 1;
+                if let Ok(i) = self.objects_in_use.binary_search_by_key(&id, |(a, _)| a) {
+                    let tracker = self.objects_in_use.get_mut(i).unwrap();
+                    tracker.1 = tracker.1.saturating_sub(1);
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.dec_use_count(item);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops a use taken for the length of a call, untracking the object again once nothing else uses it.
+    fn release_use_count(&mut self, val: &Value) {
+        match val {
+            Value::Object(id) => {
+                if let Ok(i) = self.objects_in_use.binary_search_by_key(&id, |(a, _)| a) {
+                    self.objects_in_use[i].1 = self.objects_in_use[i].1.saturating_sub(1);
+                    if self.objects_in_use[i].1 == 0 {
+                        self.objects_in_use.remove(i);
                     }
-                    _ => { unreachable!() }
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.release_use_count(item);
                 }
             }
             _ => {}
         }
     }
 
+    /// Takes a use of every object in the value, including the ones inside arrays.
     fn inc_use_count(&mut self, val: &Value){
         match val {
             Value::Object(id) => {
@@ -677,104 +2835,306 @@ impl VirtualMachine {
                     }
                 }
             }
+            Value::Array(items) => {
+                for item in items {
+                    self.inc_use_count(item);
+                }
+            }
             _ => {}
         }
     }
 
-    fn loop_run(&mut self, nodes: Vec<Node>){
+    fn check_deadline(&self) -> Result<(), RuntimeError> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(RuntimeError::Timeout),
+            _ => Ok(())
+        }
+    }
+
+    fn count_iteration(&self, iterations: &mut usize) -> Result<(), RuntimeError> {
+        self.check_deadline()?;
+        *iterations += 1;
+        match self.max_loop_iterations {
+            Some(max) if *iterations > max => Err(RuntimeError::LoopLimit(max)),
+            _ => Ok(())
+        }
+    }
+
+    fn active_scope_mut(&mut self) -> &mut HashMap<String, Value> {
+        match self.local.as_mut() {
+            Some(local) => local,
+            None => &mut self.global_variables
+        }
+    }
+
+    fn set_loop_counter(&mut self, iterations: usize) {
+        let name = self.loop_counter_name.clone();
+        self.active_scope_mut().insert(name, Value::Int(iterations as i32 - 1));
+    }
+
+    /// Runs a counted loop, putting back the counter of any enclosing loop afterwards.
+    fn with_loop_counter<R>(&mut self, run: impl FnOnce(&mut Self) -> Result<R, RuntimeError>) -> Result<R, RuntimeError> {
+        let name = self.loop_counter_name.clone();
+        let previous = self.active_scope_mut().remove(&name);
+        let res = self.with_pinned(previous.clone().unwrap_or(Value::Unit), run);
+        match previous {
+            Some(val) => { self.active_scope_mut().insert(name, val); }
+            None => { self.active_scope_mut().remove(&name); }
+        }
+        res
+    }
+
+    /// Runs one pass of a loop body, stopping early at a `break` or `continue` from any depth.
+    /// A `return` ends the loop like a `break`, but stays pending for the enclosing function.
+    fn run_loop_body(&mut self, body: &[Node], assigned: &mut Vec<String>) -> Result<Option<Signal>, RuntimeError> {
+        for node in body {
+            if let Some(var_name) = self.single_run(node.clone())? {
+                assigned.push(var_name);
+            }
+            if let Some(Signal::Return(_)) = self.signal {
+                return Ok(Some(Signal::Break(Value::Unit)));
+            }
+            if let Some(signal) = self.signal.take() {
+                return Ok(Some(signal));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Panics if a `break` or `continue` escaped every loop, or a `return` escaped every function.
+    fn check_stray_signal(&mut self) {
+        if let Some(val) = self.take_return() {
+            let message = format!("Return {} outside of function", self.stringify(&val));
+            panic!("{}", self.panic_ctx(message));
+        }
+    }
+
+    /// Takes a pending `return`, panicking on a `break` or `continue` that escaped every loop.
+    fn take_return(&mut self) -> Option<Value> {
+        match self.signal.take() {
+            Some(Signal::Return(val)) => Some(val),
+            Some(_) => panic!("{}", self.panic_ctx("Break or continue outside of loop".to_string())),
+            None => None
+        }
+    }
+
+    /// Runs until the body breaks, returning the value it broke with.
+    fn loop_run(&mut self, nodes: Vec<Node>) -> Result<Value, RuntimeError> {
         let mut assigned: Vec<String> = vec![];
+        let mut iterations = 0;
         loop {
-            for node in nodes.clone() {
-                match node {
-                    Node::Break => {
-                        self.run_gc(assigned);
-                        return;
-                    }
-                    Node::Continue => { break; }
-                    _ => {
-                        if let Some(var_name) = self.single_run(node) {
-                            assigned.push(var_name);
-                        }
-                    }
-                }
+            self.count_iteration(&mut iterations)?;
+            self.set_loop_counter(iterations);
+            if let Some(Signal::Break(val)) = self.run_loop_body(&nodes, &mut assigned)? {
+                self.run_gc(assigned);
+                return Ok(val);
             }
         }
     }
 
-    fn while_loop(&mut self, condition: Eval, body: Vec<Node>){
+    fn while_loop(&mut self, condition: Eval, body: Vec<Node>) -> Result<(), RuntimeError> {
         let mut assigned: Vec<String> = vec![];
-        while self.eval(condition.clone()) == Value::Bool(true) {
-            for node in body.clone() {
-                match node {
-                    Node::Break => {
-                        self.run_gc(assigned);
-                        return;
-                    }
-                    Node::Continue => { break; }
-                    _ => {
-                        if let Some(var_name) = self.single_run(node) {
-                            assigned.push(var_name);
-                        }
-                    }
+        let mut iterations = 0;
+        while self.eval(condition.clone())? == Value::Bool(true) {
+            self.count_iteration(&mut iterations)?;
+            self.set_loop_counter(iterations);
+            if let Some(Signal::Break(_)) = self.run_loop_body(&body, &mut assigned)? {
+                break;
+            }
+        }
+
+        self.run_gc(assigned);
+        Ok(())
+    }
+
+    fn times(&mut self, count: usize, body: Vec<Node>) -> Result<(), RuntimeError> {
+        let mut assigned: Vec<String> = vec![];
+        let mut iterations = 0;
+        while iterations < count {
+            self.count_iteration(&mut iterations)?;
+            self.set_loop_counter(iterations);
+            if let Some(Signal::Break(_)) = self.run_loop_body(&body, &mut assigned)? {
+                break;
+            }
+        }
+
+        self.run_gc(assigned);
+        Ok(())
+    }
+
+    /// Like `while_loop` with the check after the body. `continue` skips to the check.
+    fn repeat_until(&mut self, body: Vec<Node>, condition: Eval) -> Result<(), RuntimeError> {
+        let mut assigned: Vec<String> = vec![];
+        let mut iterations = 0;
+        loop {
+            self.count_iteration(&mut iterations)?;
+            self.set_loop_counter(iterations);
+            if let Some(Signal::Break(_)) = self.run_loop_body(&body, &mut assigned)? {
+                break;
+            }
+            if self.eval(condition.clone())? == Value::Bool(true) {
+                break;
+            }
+        }
+
+        self.run_gc(assigned);
+        Ok(())
+    }
+
+    fn for_loop(&mut self, var_name: String, iterable: Eval, body: Vec<Node>) -> Result<(), RuntimeError> {
+        let items = match self.eval(iterable)? {
+            Value::Array(items) => items,
+            val => return Err(RuntimeError::NotIterable(val.type_name()))
+        };
+
+        self.with_pinned(Value::Array(items.clone()), |vm| {
+            let mut assigned: Vec<String> = vec![];
+            let mut iterations = 0;
+            for item in items {
+                vm.count_iteration(&mut iterations)?;
+                vm.assign_variable(var_name.clone(), item);
+                if let Some(Signal::Break(_)) = vm.run_loop_body(&body, &mut assigned)? {
+                    break;
                 }
             }
+
+            vm.run_gc(assigned);
+            Ok(())
+        })
+    }
+
+    /// Like `for_loop` over the range, without building the array first.
+    fn for_range(&mut self, var_name: String, bounds: [Eval; 3], body: Vec<Node>) -> Result<(), RuntimeError> {
+        let mut ints = [0; 3];
+        for (int, bound) in ints.iter_mut().zip(bounds) {
+            *int = match self.eval(bound)? {
+                Value::Int(val) => val,
+                val => return Err(RuntimeError::RangeBound(val.type_name()))
+            };
+        }
+        let [start, stop, step] = ints;
+        if step == 0 {
+            return Err(RuntimeError::ZeroStep);
+        }
+
+        let mut assigned: Vec<String> = vec![];
+        let mut iterations = 0;
+        let mut current = start as i64;
+        while (step > 0 && current < stop as i64) || (step < 0 && current > stop as i64) {
+            self.count_iteration(&mut iterations)?;
+            self.assign_variable(var_name.clone(), Value::Int(current as i32));
+            if let Some(Signal::Break(_)) = self.run_loop_body(&body, &mut assigned)? {
+                break;
+            }
+            current += step as i64;
         }
 
         self.run_gc(assigned);
+        Ok(())
+    }
+
+    fn assign_variable(&mut self, var_name: String, val: Value) {
+        if self.local.is_some() && self.global_variables.contains_key(&*var_name) {
+            panic!("{}", self.panic_ctx(format!("Variable {} already exists globally", var_name)));
+        }
+
+        // Variables hold a use of the objects in them, like object fields do.
+        self.inc_use_count(&val);
+        let old = match self.local.as_mut() {
+            Some(local) => local.insert(var_name, val),
+            None => self.global_variables.insert(var_name, val)
+        };
+        if let Some(old) = old {
+            self.dec_use_count(&old);
+        }
+    }
+
+    /// Appends the executing statement and the variables in scope to a panic message.
+    fn panic_ctx(&self, msg: String) -> String {
+        let (scope, mut names) = match self.local.as_ref() {
+            Some(local) => ("locals", local.keys().cloned().collect::<Vec<_>>()),
+            None => ("globals", self.global_variables.keys().cloned().collect::<Vec<_>>())
+        };
+        names.sort();
+
+        format!("{} (while running {}, {}: [{}])", msg, self.executing.unwrap_or("nothing"), scope, names.join(", "))
+    }
+
+    pub fn single_run(&mut self, node: Node) -> Result<Option<String>, RuntimeError> {
+        if let (Some(log), 1..) = (self.execution_log.as_mut(), self.execution_log_cap) {
+            if log.len() == self.execution_log_cap {
+                log.pop_front();
+            }
+            log.push_back(node.kind());
+        }
+
+        let previous = self.executing.replace(node.kind());
+        let res = self.run_node(node);
+        self.executing = previous;
+        // Between statements of a function body nothing of its own is held mid-expression.
+        if self.expression_depth == 0 {
+            self.collect_functions();
+        }
+        res
     }
 
-    fn single_run(&mut self, node: Node) -> Option<String> {
+    fn run_node(&mut self, node: Node) -> Result<Option<String>, RuntimeError> {
+        self.check_deadline()?;
         // also handle local variables
         match node {
             Node::Assign(var_name, var_val) => {
-                if self.local.is_some(){
-                    if self.global_variables.contains_key(&*var_name){
-                        panic!("Variable {} already exists globally", var_name);
-                    }
+                let val = self.eval(var_val)?;
+                self.assign_variable(var_name, val);
+            }
+            Node::AssignDefault(var_name, var_val) => {
+                let exists = match self.local.as_ref() {
+                    Some(local) => local.contains_key(&var_name),
+                    None => self.global_variables.contains_key(&var_name)
+                };
 
-                    let val = self.eval(var_val);
-                    self.local.as_mut().unwrap().insert(var_name, val);
+                if !exists {
+                    let val = self.eval(var_val)?;
+                    self.assign_variable(var_name, val);
+                }
+            }
+            Node::DestructureAssign(var_names, var_val) => {
+                let values = match self.eval(var_val)? {
+                    Value::Array(values) => values,
+                    val => return Err(RuntimeError::DestructureType(val.type_name()))
+                };
 
-                } else {
-                    let val = self.eval(var_val);
-                    self.global_variables.insert(var_name.clone(), val);
+                if values.len() != var_names.len() {
+                    return Err(RuntimeError::DestructureLength { expected: var_names.len(), found: values.len() });
+                }
+
+                for (var_name, val) in var_names.into_iter().zip(values) {
+                    self.assign_variable(var_name, val);
                 }
             }
             Node::Unassign(var_name) => {
-                if self.local.is_some(){
-                    match self.local.as_mut().unwrap().remove(&*var_name) {
-                        Some(val) => {
-                            self.dec_use_count(&val);
-                        }
-                        None => {
-                            match self.global_variables.remove(&*var_name) {
-                                Some(val) => { self.dec_use_count(&val); }
-                                None => { panic!("Variable {} does not exist", var_name); }
-                            }
-                        }
-                    }
-                } else {
-                    match self.global_variables.remove(&*var_name) {
-                        Some(val) => { self.dec_use_count(&val); }
-                        None => { panic!("Variable {} does not exist", var_name); }
-                    }
+                let removed = match self.local.as_mut().and_then(|local| local.remove(&*var_name)) {
+                    Some(val) => Some(val),
+                    None => self.global_variables.remove(&*var_name)
+                };
+                match removed {
+                    Some(val) => { self.dec_use_count(&val); }
+                    None => { panic!("{}", self.panic_ctx(format!("Variable {} does not exist", var_name))); }
                 }
             }
             Node::CreateObject(ptr, fields) => {
-                let obj_loc = self.eval(ptr);
-                let ptr;
-                match obj_loc {
-                    Value::Int(id) => { ptr = id as usize; }
-                    _ => { unreachable!() }
-                }
+                let obj_loc = self.eval(ptr)?;
+                let ptr = match obj_loc {
+                    Value::Int(id) => id as usize,
+                    val => panic!("{}", self.panic_ctx(format!("Object id must be an int, found {}", val.type_name())))
+                };
 
                 if self.objects.contains_key(&ptr) {
-                    panic!("Object already exists, Deallocate first");
+                    panic!("{}", self.panic_ctx("Object already exists, Deallocate first".to_string()));
                 }
 
                 let mut value = HashMap::new();
                 for field in fields {
-                    let res = self.eval(field.1);
+                    let res = self.eval(field.1)?;
                     self.inc_use_count(&res);
                     value.insert(field.0, res);
                 }
@@ -782,16 +3142,17 @@ impl VirtualMachine {
                 self.objects.insert(ptr, object);
             }
             Node::DeleteObject(ptr) => {
-                let obj_loc = self.eval(ptr);
-                let ptr;
-                match obj_loc {
-                    Value::Int(id) => { ptr = id as usize; }
-                    _ => { unreachable!() }
-                }
+                let obj_loc = self.eval(ptr)?;
+                let ptr = match obj_loc {
+                    Value::Int(id) => id as usize,
+                    val => panic!("{}", self.panic_ctx(format!("Object id must be an int, found {}", val.type_name())))
+                };
 
+                self.frozen_objects.remove(&ptr);
                 match self.objects.remove(&ptr){
                     None => {}
                     Some(old) => {
+                        self.object_ids.release(ptr);
                         for (_, val) in old.fields {
                             self.dec_use_count(&val);
                         }
@@ -801,83 +3162,136 @@ impl VirtualMachine {
             Node::Conditional(conditions, else_block) => {
                 let mut ran = false;
                 for condition in conditions {
-                    if self.eval(condition.0) == Value::Bool(true) {
-                        self.multi_run(condition.1);
+                    if self.eval(condition.0)? == Value::Bool(true) {
+                        self.multi_run(condition.1)?;
                         ran = true;
                         break;
                     }
                 }
 
-                if !ran && !else_block.is_empty() {
-                    self.multi_run(else_block);
+                if let (false, Some(else_block)) = (ran, else_block) {
+                    self.multi_run(else_block)?;
                 }
             }
+            Node::Guard(condition, body) => {
+                if self.eval(condition)? != Value::Bool(true) {
+                    self.multi_run(body)?;
+                }
+            }
+            Node::Switch(subject, cases, default) => {
+                let subject = self.eval(subject)?;
+                self.with_pinned(subject.clone(), |vm| -> Result<(), RuntimeError> {
+                    let mut ran = false;
+                    'cases: for (keys, body) in cases {
+                        for key in keys {
+                            if vm.eval(key)? == subject {
+                                vm.multi_run(body)?;
+                                ran = true;
+                                break 'cases;
+                            }
+                        }
+                    }
+
+                    if !ran && !default.is_empty() {
+                        vm.multi_run(default)?;
+                    }
+                    Ok(())
+                })?;
+            }
             Node::Loop(nodes) => {
-                self.loop_run(nodes);
+                self.with_loop_counter(|vm| vm.loop_run(nodes))?;
             }
             Node::WhileLoop(condition, body) => {
-                self.while_loop(condition, body);
+                self.with_loop_counter(|vm| vm.while_loop(condition, body))?;
+            }
+            Node::For(var_name, iterable, body) => {
+                self.for_loop(var_name, iterable, body)?;
+            }
+            Node::ForRange(var_name, start, stop, step, body) => {
+                self.for_range(var_name, [start, stop, step], body)?;
+            }
+            Node::Times(count, body) => {
+                let count = match self.eval(count)? {
+                    Value::Int(count) => repeat_count(count)?,
+                    val => return Err(RuntimeError::CountType(val.type_name()))
+                };
+                self.with_loop_counter(|vm| vm.times(count, body))?;
+            }
+            Node::RepeatUntil(body, condition) => {
+                self.with_loop_counter(|vm| vm.repeat_until(body, condition))?;
+            }
+            Node::Break => {
+                self.signal = Some(Signal::Break(Value::Unit));
+            }
+            Node::BreakWith(val) => {
+                let val = self.eval(val)?;
+                self.signal = Some(Signal::Break(val));
+            }
+            Node::Continue => {
+                self.signal = Some(Signal::Continue);
+            }
+            Node::FnDef(name, args, body) => {
+                self.add_defined_functions(vec![DefinedFunction::new(name, args, body, false)]);
+            }
+            Node::Return(val) => {
+                let val = self.eval(val)?;
+                self.signal = Some(Signal::Return(val));
             }
-            Node::For(_, _, _) => { unimplemented!() }
-            Node::Break => { unreachable!("Break outside of loop") }
-            Node::Continue => { unreachable!("Continue outside of loop") }
-            Node::FnDef(_, _, _) => { unimplemented!()}
-            Node::Return(_) => { unreachable!("Return outside of function") }
             Node::FnCall(name, args) => {
-                if !self.functions.contains_key(&*name){
-                    panic!("Function {} does not exist", name);
-                }
-
-                let function = self.functions.remove(&*name).unwrap();
-
-                if function.args_len() != args.len() && !function.is_variadic() {
-                    panic!("Function {} takes {} arguments, {} given", name, function.args_len(), args.len());
-                }
-
-                function.call(self, args);
-
-                self.functions.insert(name, function);
+                self.call_function(name, args)?;
             }
             Node::SetMember(obj_id, member, val) => {
-                let obj_loc = self.eval(obj_id);
-                let obj_id;
-                match obj_loc {
-                    Value::Int(id) => { obj_id = id as usize; }
-                    Value::String(var_name) => {
-                        match *self.global_variables.get(&var_name).unwrap() {
-                            Value::Object(id) => { obj_id = id as usize; }
-                            _ => { unreachable!()}
-                        }
-                    }
-                    _ => { unreachable!() }
+                let obj_loc = self.eval(obj_id)?;
+                let obj_id = self.resolve_object(obj_loc);
+                if self.frozen_objects.contains(&obj_id) {
+                    return Err(RuntimeError::FrozenObject(obj_id));
                 }
-                let res = self.eval(val);
+                let has_field = match self.objects.get(&obj_id) {
+                    Some(obj) => obj.fields.contains_key(&member),
+                    None => panic!("{}", self.panic_ctx(format!("Object {} does not exist", obj_id)))
+                };
+                if self.strict_members && !has_field {
+                    return Err(RuntimeError::UnknownField { object: obj_id, field: member });
+                }
+
+                let res = self.eval(val)?;
                 self.inc_use_count(&res);
 
-                let obj = self.objects.get_mut(&(obj_id as usize)).unwrap();
-                obj.fields.insert(member, res);
+                let old = match self.objects.get_mut(&obj_id) {
+                    Some(obj) => obj.fields.insert(member, res),
+                    None => panic!("{}", self.panic_ctx(format!("Object {} was deleted while setting {}", obj_id, member)))
+                };
+                if let Some(old) = old {
+                    self.dec_use_count(&old);
+                }
             }
         }
-        None
+        Ok(None)
     }
 
-    fn multi_run(&mut self, nodes: Vec<Node>){
+    fn multi_run(&mut self, nodes: Vec<Node>) -> Result<(), RuntimeError> {
         let mut assigned = vec![];
         for node in nodes {
-            if let Some(var) = self.single_run(node) {
+            if let Some(var) = self.single_run(node)? {
                 assigned.push(var);
             }
+            // Leave the signal for the enclosing loop or function to pick up.
+            if self.signal.is_some() {
+                break;
+            }
         }
 
         self.run_gc(assigned);
+        Ok(())
     }
 
-    pub fn run(&mut self, nodes: Vec<Node>) {
+    pub fn run(&mut self, nodes: Vec<Node>) -> Result<(), RuntimeError> {
         let mut assigned = vec![];
         for node in nodes {
-            if let Some(var) = self.single_run(node) {
+            if let Some(var) = self.single_run(node)? {
                 assigned.push(var);
             }
+            self.check_stray_signal();
         }
 
         // println!("{:#?}", self);
@@ -890,5 +3304,67 @@ impl VirtualMachine {
         for (obj_id , obj) in &self.objects {
             eprintln!("Object {}: {:?}", obj_id, obj);
         }
+        Ok(())
+    }
+
+    /// Loads a program to be executed one statement at a time with `step`.
+    pub fn load(&mut self, nodes: Vec<Node>) {
+        self.program = nodes;
+        self.instruction_pointer = 0;
+        self.paused_at = None;
+    }
+
+    /// Runs the next statement of the loaded program, or returns `None` once it has finished.
+    /// A failing statement is still stepped over, so the host decides whether to carry on.
+    pub fn step(&mut self) -> Option<StepResult> {
+        let index = self.instruction_pointer;
+        let node = self.program.get(index)?.clone();
+        self.instruction_pointer += 1;
+
+        let result = self.single_run(node).map(|_| ());
+        self.check_stray_signal();
+        Some(StepResult { index, result })
+    }
+
+    /// Runs the loaded program until the next breakpoint, returning its index, or `None` once finished.
+    /// Calling it again resumes from the breakpoint it paused at.
+    pub fn run_until_breakpoint(&mut self) -> Result<Option<usize>, RuntimeError> {
+        while self.instruction_pointer < self.program.len() {
+            let index = self.instruction_pointer;
+            if self.breakpoints.contains(&index) && self.paused_at != Some(index) {
+                self.paused_at = Some(index);
+                return Ok(Some(index));
+            }
+
+            self.paused_at = None;
+            if let Some(step) = self.step() {
+                step.result?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Registers the top-level `FnDef`s of the loaded program, then calls its `main` function with
+    /// `args` and returns what `main` returned. Other top-level statements are not run.
+    pub fn run_main(&mut self, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        if self.program.is_empty() {
+            return Err(RuntimeError::NoProgram);
+        }
+        let program = std::mem::take(&mut self.program);
+        self.load_functions_from(&program);
+        self.program = program;
+
+        if !self.functions.contains_key("main") {
+            return Err(RuntimeError::MissingMain);
+        }
+        self.call_with_values("main", args)
+    }
+
+    /// Like `run`, but aborts with `RuntimeError::Timeout` once `timeout` has elapsed.
+    pub fn run_with_timeout(&mut self, nodes: Vec<Node>, timeout: Duration) -> Result<(), RuntimeError> {
+        self.deadline = Some(Instant::now() + timeout);
+        let res = self.run(nodes);
+        self.deadline = None;
+        res
     }
 }
\ No newline at end of file
@@ -0,0 +1,34 @@
+use qahlvm::analysis::*;
+use qahlvm::ast::*;
+
+#[test]
+fn unused_variable_and_function() {
+    let program = vec![
+        Node::FnDef("helper".to_string(), vec!["x".to_string()], vec![
+            Node::Return(Eval::VarRef("x".to_string())),
+        ]),
+        Node::FnDef("used".to_string(), vec![], vec![]),
+        Node::Assign("unused".to_string(), Eval::Int(1)),
+        Node::Assign("count".to_string(), Eval::Int(2)),
+        Node::FnCall("used".to_string(), vec![]),
+        Node::FnCall("println".to_string(), vec![Eval::VarRef("count".to_string())]),
+    ];
+
+    assert_eq!(find_unused(&program), vec![
+        Finding::UnusedVariable("unused".to_string()),
+        Finding::UnusedFunction("helper".to_string()),
+    ]);
+}
+
+#[test]
+fn everything_used() {
+    let program = vec![
+        Node::FnDef("double".to_string(), vec!["x".to_string()], vec![]),
+        Node::Assign("f".to_string(), Eval::FnRef("double".to_string())),
+        Node::For("item".to_string(), Eval::Array(vec![Eval::Int(1)]), vec![
+            Node::FnCall("f".to_string(), vec![Eval::VarRef("item".to_string())]),
+        ]),
+    ];
+
+    assert_eq!(find_unused(&program), vec![]);
+}
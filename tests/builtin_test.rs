@@ -0,0 +1,1278 @@
+use qahlvm::ast::*;
+use qahlvm::error::*;
+use qahlvm::vm::*;
+
+mod common;
+
+use common::{call, eval, eval_ints, ints, string, var};
+
+#[test]
+fn merge() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![
+            ("a".to_string(), Eval::Int(1)),
+            ("b".to_string(), Eval::Int(2)),
+        ]),
+        Node::CreateObject(Eval::Int(2), vec![
+            ("b".to_string(), Eval::Int(3)),
+            ("c".to_string(), Eval::Int(4)),
+        ]),
+        Node::Assign(
+            "merged".to_string(),
+            Eval::FnCall("merge".to_string(), vec![common::object(1), common::object(2)])
+        ),
+    ]).unwrap();
+
+    let id = match vm.global_variables["merged"] {
+        Value::Object(id) => id,
+        ref val => panic!("Expected object, got {:?}", val)
+    };
+    assert!(id != 1 && id != 2);
+
+    let fields = &vm.objects[&id].fields;
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields["a"], Value::Int(1));
+    assert_eq!(fields["b"], Value::Int(3));
+    assert_eq!(fields["c"], Value::Int(4));
+    assert_eq!(vm.objects[&1].fields["b"], Value::Int(2));
+}
+
+#[test]
+fn merge_copies_nested_objects() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(3), vec![("depth".to_string(), Eval::Int(1))]),
+        Node::CreateObject(Eval::Int(1), vec![("nested".to_string(), common::object(3))]),
+        Node::CreateObject(Eval::Int(2), vec![("other".to_string(), Eval::Int(2))]),
+    ]).unwrap();
+
+    let merged = eval(&mut vm, call("merge", vec![common::object(1), common::object(2)]));
+    let Ok(Value::Object(id)) = merged else { panic!("merge returned {:?}", merged) };
+    let Value::Object(nested) = vm.objects[&id].fields["nested"] else { panic!("nested field is not an object") };
+    assert_ne!(nested, 3);
+
+    vm.run(vec![Node::SetMember(Eval::Int(nested as i32), "depth".to_string(), Eval::Int(9))]).unwrap();
+    assert_eq!(vm.objects[&nested].fields["depth"], Value::Int(9));
+    assert_eq!(vm.objects[&3].fields["depth"], Value::Int(1));
+}
+
+#[test]
+fn len_wrong_argument_type() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    let err = vm.run(vec![
+        Node::Assign("length".to_string(), Eval::FnCall("len".to_string(), vec![Eval::Int(5)])),
+    ]).unwrap_err();
+
+    assert_eq!(err, RuntimeError::ArgumentType {
+        function: "len".to_string(),
+        index: 0,
+        expected: "string or array",
+        found: "int",
+    });
+    assert_eq!(err.to_string(), "Function len expected string or array for argument 1, found int");
+}
+
+#[test]
+fn println_float_keeps_decimal() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let output = common::SharedBuffer::default();
+    vm.set_output(output.clone());
+
+    vm.run(vec![
+        Node::FnCall("println".to_string(), vec![Eval::Float(1.0)]),
+        Node::FnCall("println".to_string(), vec![Eval::Int(1)]),
+        Node::FnCall("println".to_string(), vec![Eval::Array(vec![Eval::Float(2.0), Eval::Float(2.5)])]),
+    ]).unwrap();
+
+    assert_eq!(output.contents(), "1.0\n1\n[2.0, 2.5]\n");
+}
+
+#[test]
+fn eprintln_writes_to_error_output() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let output = common::SharedBuffer::default();
+    let error_output = common::SharedBuffer::default();
+    vm.set_output(output.clone());
+    vm.set_error_output(error_output.clone());
+
+    vm.run(vec![
+        Node::FnCall("println".to_string(), vec![string("result")]),
+        Node::FnCall("eprintln".to_string(), vec![string("warning:"), Eval::Int(1)]),
+    ]).unwrap();
+
+    assert_eq!(output.contents(), "result\n");
+    assert_eq!(error_output.contents(), "warning: 1\n");
+}
+
+fn numeric_functions() -> Vec<BuiltInFunction> {
+    vec![
+        BuiltInFunction::new("double".to_string(), 1, false, |vm, args| {
+            Ok(Some(Value::Int(vm.eval(args[0].clone())?.as_int() * 2)))
+        }),
+        BuiltInFunction::new("inc".to_string(), 1, false, |vm, args| {
+            Ok(Some(Value::Int(vm.eval(args[0].clone())?.as_int() + 1)))
+        }),
+        BuiltInFunction::new("square".to_string(), 1, false, |vm, args| {
+            let val = vm.eval(args[0].clone())?.as_int();
+            Ok(Some(Value::Int(val * val)))
+        }),
+    ]
+}
+
+#[test]
+fn compose() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(numeric_functions());
+
+    vm.run(vec![
+        Node::Assign(
+            "double_after_inc".to_string(),
+            Eval::FnCall("compose".to_string(), vec![Eval::FnRef("double".to_string()), Eval::FnRef("inc".to_string())])
+        ),
+        Node::Assign("res".to_string(), Eval::FnCall("double_after_inc".to_string(), vec![Eval::Int(4)])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["res"], Value::Int(10));
+}
+
+#[test]
+fn unused_compositions_are_collected() {
+    let add = |n: i32| Eval::Closure(vec!["x".to_string()], vec![
+        Node::Return(Eval::Add(Box::new(Eval::VarRef("x".to_string())), Box::new(Eval::Int(n)))),
+    ]);
+    let compose = |inner: Eval| call("compose", vec![Eval::FnRef("double".to_string()), inner]);
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(numeric_functions());
+
+    vm.run(vec![
+        Node::Assign("kept".to_string(), compose(add(1))),
+        Node::Times(Eval::Int(300), vec![
+            Node::Assign("tmp".to_string(), compose(add(2))),
+            Node::Assign("res".to_string(), call("tmp", vec![Eval::Int(1)])),
+        ]),
+    ]).unwrap();
+
+    let generated = vm.functions.keys().filter(|name| name.starts_with("compose(") || name.starts_with("<closure")).count();
+    assert!(generated <= 128, "{} generated functions left", generated);
+    assert_eq!(vm.global_variables["res"], Value::Int(6));
+    // The composition keeps the closure it wraps alive.
+    assert_eq!(eval(&mut vm, call("kept", vec![Eval::Int(4)])), Ok(Value::Int(10)));
+}
+
+#[test]
+fn compose_running_function() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(numeric_functions());
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("composer".to_string(), vec!["f".to_string()], vec![
+            Node::Return(call("compose", vec![Eval::FnRef("double".to_string()), Eval::VarRef("f".to_string())])),
+        ], false),
+    ]);
+
+    vm.global_variables.insert("me".to_string(), Value::Function("composer".to_string()));
+
+    assert_eq!(
+        eval(&mut vm, call("composer", vec![Eval::VarRef("me".to_string())])),
+        Err(RuntimeError::FunctionUnavailable("composer".to_string()))
+    );
+}
+
+#[test]
+fn pipe() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(numeric_functions());
+
+    vm.run(vec![
+        Node::Assign("res".to_string(), Eval::FnCall("pipe".to_string(), vec![
+            Eval::Int(3),
+            Eval::FnRef("inc".to_string()),
+            Eval::FnRef("double".to_string()),
+            Eval::FnRef("square".to_string()),
+        ])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["res"], Value::Int(64));
+}
+
+#[test]
+fn builtin_error_propagates() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(vec![
+        BuiltInFunction::new("fail".to_string(), 0, false, |_, _| {
+            Err(RuntimeError::Io("disk unplugged".to_string()))
+        }),
+    ]);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("wrapper".to_string(), vec![], vec![
+            Node::FnCall("fail".to_string(), vec![]),
+            Node::Assign("unreached".to_string(), Eval::Int(1)),
+        ], false),
+    ]);
+
+    let err = vm.run(vec![
+        Node::FnCall("wrapper".to_string(), vec![]),
+        Node::Assign("after".to_string(), Eval::Int(1)),
+    ]).unwrap_err();
+
+    assert_eq!(err, RuntimeError::Io("disk unplugged".to_string()));
+    assert!(!vm.global_variables.contains_key("after"));
+    assert!(vm.local.is_none());
+}
+
+#[test]
+fn capped_variadic_rejects_extra_arguments() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(vec![
+        BuiltInFunction::new("count".to_string(), 1, true, |_, args| Ok(Some(Value::Int(args.len() as i32))))
+            .with_max_args(3),
+    ]);
+
+    vm.run(vec![
+        Node::Assign("three".to_string(), Eval::FnCall("count".to_string(), vec![Eval::Int(1), Eval::Int(2), Eval::Int(3)])),
+    ]).unwrap();
+    assert_eq!(vm.global_variables["three"], Value::Int(3));
+
+    let err = vm.run(vec![
+        Node::FnCall("count".to_string(), vec![Eval::Int(1), Eval::Int(2), Eval::Int(3), Eval::Int(4)]),
+    ]).unwrap_err();
+    assert_eq!(err, RuntimeError::TooManyArguments { function: "count".to_string(), max: 3, found: 4 });
+    assert!(vm.functions.contains_key("count"));
+}
+
+#[test]
+fn input_print_takes_one_prompt() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let output = common::SharedBuffer::default();
+    vm.set_output(output.clone());
+
+    assert_eq!(
+        eval(&mut vm, call("input_print", vec![string("name: "), string("extra")])),
+        Err(RuntimeError::TooManyArguments { function: "input_print".to_string(), max: 1, found: 2 })
+    );
+    assert_eq!(output.contents(), "");
+}
+
+#[test]
+fn replace() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(
+        eval(&mut vm, call("replace", vec![string("a.b.c"), string("."), string("/")])),
+        Ok(Value::String("a/b/c".to_string()))
+    );
+    assert!(matches!(
+        eval(&mut vm, call("replace", vec![string("abc"), string(""), string("/")])),
+        Err(RuntimeError::InvalidArgument { .. })
+    ));
+}
+
+#[test]
+fn trim_one_side() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(eval(&mut vm, call("trim_start", vec![string("  hi  ")])), Ok(Value::String("hi  ".to_string())));
+    assert_eq!(eval(&mut vm, call("trim_end", vec![string("  hi  ")])), Ok(Value::String("  hi".to_string())));
+}
+
+#[test]
+fn starts_with_and_ends_with() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let check = |vm: &mut VirtualMachine, name: &str, val: &str, affix: &str| {
+        eval(vm, call(name, vec![string(val), string(affix)])).unwrap()
+    };
+
+    assert_eq!(check(&mut vm, "starts_with", "/api/users", "/api"), Value::Bool(true));
+    assert_eq!(check(&mut vm, "starts_with", "/api/users", "users"), Value::Bool(false));
+    assert_eq!(check(&mut vm, "starts_with", "日本語テキスト", "日本"), Value::Bool(true));
+    assert_eq!(check(&mut vm, "ends_with", "report.pdf", ".pdf"), Value::Bool(true));
+    assert_eq!(check(&mut vm, "ends_with", "report.pdf", ".txt"), Value::Bool(false));
+    assert_eq!(check(&mut vm, "ends_with", "héllo wörld", "wörld"), Value::Bool(true));
+}
+
+#[test]
+fn object_from_pairs() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("prefix".to_string(), string("field_")),
+        Node::Assign("obj".to_string(), call("object_from_pairs", vec![
+            Eval::Array(vec![
+                Eval::Add(Box::new(Eval::VarRef("prefix".to_string())), Box::new(string("a"))),
+                Eval::Add(Box::new(Eval::VarRef("prefix".to_string())), Box::new(string("b"))),
+            ]),
+            Eval::Array(vec![Eval::Int(1), Eval::Int(2)]),
+        ])),
+    ]).unwrap();
+
+    let id = match vm.global_variables["obj"] {
+        Value::Object(id) => id,
+        ref val => panic!("Expected object, got {:?}", val)
+    };
+    assert_eq!(vm.objects[&id].fields["field_a"], Value::Int(1));
+    assert_eq!(vm.objects[&id].fields["field_b"], Value::Int(2));
+}
+
+#[test]
+fn unique() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let items = [1, 1, 2, 3, 3].into_iter().map(Eval::Int).collect();
+    assert_eq!(
+        eval(&mut vm, call("unique", vec![Eval::Array(items)])),
+        Ok(Value::Array(vec![Value::Int(1), Value::Int(2), Value::Int(3)]))
+    );
+    assert_eq!(
+        eval(&mut vm, call("unique", vec![Eval::Array(vec![Eval::Array(vec![])])])),
+        Err(RuntimeError::Unhashable("array"))
+    );
+}
+
+#[test]
+fn group_by() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(vec![
+        BuiltInFunction::new("parity".to_string(), 1, false, |vm, args| {
+            let val = vm.eval(args[0].clone())?.as_int();
+            Ok(Some(Value::String(if val % 2 == 0 { "even" } else { "odd" }.to_string())))
+        }),
+    ]);
+
+    let items = (1..=5).map(Eval::Int).collect();
+    let id = match eval(&mut vm, call("group_by", vec![Eval::FnRef("parity".to_string()), Eval::Array(items)])) {
+        Ok(Value::Object(id)) => id,
+        res => panic!("Expected object, got {:?}", res)
+    };
+
+    let fields = &vm.objects[&id].fields;
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields["odd"], Value::Array(vec![Value::Int(1), Value::Int(3), Value::Int(5)]));
+    assert_eq!(fields["even"], Value::Array(vec![Value::Int(2), Value::Int(4)]));
+}
+
+#[test]
+fn exists() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![Node::Assign("defined".to_string(), Eval::Int(1))]).unwrap();
+
+    assert_eq!(eval(&mut vm, call("exists", vec![string("defined")])), Ok(Value::Bool(true)));
+    assert_eq!(eval(&mut vm, call("exists", vec![string("undefined")])), Ok(Value::Bool(false)));
+}
+
+#[test]
+fn pad_and_fixed() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(eval(&mut vm, call("pad", vec![string("7"), Eval::Int(3)])), Ok(Value::String("  7".to_string())));
+    assert_eq!(eval(&mut vm, call("pad", vec![Eval::Int(7), Eval::Int(-3)])), Ok(Value::String("7  ".to_string())));
+    assert_eq!(eval(&mut vm, call("pad", vec![Eval::Float(1.0), Eval::Int(2)])), Ok(Value::String("1.0".to_string())));
+    assert_eq!(eval(&mut vm, call("fixed", vec![Eval::Float(std::f32::consts::PI), Eval::Int(2)])), Ok(Value::String("3.14".to_string())));
+    assert_eq!(eval(&mut vm, call("fixed", vec![Eval::Float(2.0), Eval::Int(0)])), Ok(Value::String("2".to_string())));
+}
+
+#[test]
+fn abs_diff_and_clamp01() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(eval(&mut vm, call("abs_diff", vec![Eval::Int(3), Eval::Int(7)])), Ok(Value::Int(4)));
+    assert_eq!(eval(&mut vm, call("abs_diff", vec![Eval::Float(2.5), Eval::Int(1)])), Ok(Value::Float(1.5)));
+    assert_eq!(
+        eval(&mut vm, call("abs_diff", vec![Eval::Int(i32::MIN), Eval::Int(1)])),
+        Err(RuntimeError::invalid_argument("abs_diff", "the difference does not fit in an int"))
+    );
+    assert_eq!(eval(&mut vm, call("abs_diff", vec![Eval::Int(i32::MIN), Eval::Int(-1)])), Ok(Value::Int(i32::MAX)));
+    assert_eq!(eval(&mut vm, call("clamp01", vec![Eval::Float(1.5)])), Ok(Value::Float(1.0)));
+    assert_eq!(eval(&mut vm, call("clamp01", vec![Eval::Int(-2)])), Ok(Value::Float(0.0)));
+    assert_eq!(eval(&mut vm, call("clamp01", vec![Eval::Float(0.25)])), Ok(Value::Float(0.25)));
+    assert_eq!(
+        eval(&mut vm, call("clamp01", vec![string("1")])),
+        Err(RuntimeError::argument_type("clamp01", 0, "number", &Value::String("1".to_string())))
+    );
+}
+
+#[test]
+fn lerp() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(eval(&mut vm, call("lerp", vec![Eval::Int(0), Eval::Int(10), Eval::Float(0.5)])), Ok(Value::Float(5.0)));
+    assert_eq!(eval(&mut vm, call("lerp", vec![Eval::Int(0), Eval::Int(10), Eval::Float(1.5)])), Ok(Value::Float(15.0)));
+}
+
+#[test]
+fn from_template() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![
+            ("width".to_string(), Eval::Int(80)),
+            ("height".to_string(), Eval::Int(24)),
+            ("title".to_string(), string("untitled")),
+        ]),
+        Node::CreateObject(Eval::Int(2), vec![("title".to_string(), string("main"))]),
+        Node::CreateObject(Eval::Int(3), vec![("depth".to_string(), Eval::Int(1))]),
+    ]).unwrap();
+
+    let id = match eval(&mut vm, call("from_template", vec![common::object(1), common::object(2)])) {
+        Ok(Value::Object(id)) => id,
+        res => panic!("Expected object, got {:?}", res)
+    };
+
+    let fields = &vm.objects[&id].fields;
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields["width"], Value::Int(80));
+    assert_eq!(fields["title"], Value::String("main".to_string()));
+    assert_eq!(vm.objects[&1].fields["title"], Value::String("untitled".to_string()));
+
+    assert_eq!(
+        eval(&mut vm, call("from_template", vec![common::object(1), common::object(3)])),
+        Err(RuntimeError::invalid_argument("from_template", "the template has no field depth"))
+    );
+}
+
+#[test]
+fn filter_map() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(vec![
+        BuiltInFunction::new("even_square".to_string(), 1, false, |vm, args| {
+            let val = vm.eval(args[0].clone())?.as_int();
+            Ok(Some(if val % 2 == 0 { Value::Int(val * val) } else { Value::Null }))
+        }),
+    ]);
+
+    let items = (1..=6).map(Eval::Int).collect();
+    assert_eq!(
+        eval(&mut vm, call("filter_map", vec![Eval::FnRef("even_square".to_string()), Eval::Array(items)])),
+        Ok(Value::Array(vec![Value::Int(4), Value::Int(16), Value::Int(36)]))
+    );
+}
+
+#[test]
+fn max_by_and_min_by() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let words = || Eval::Array(vec![string("fig"), string("banana"), string("kiwi"), string("cherry")]);
+
+    assert_eq!(
+        eval(&mut vm, call("max_by", vec![Eval::FnRef("len".to_string()), words()])),
+        Ok(Value::String("banana".to_string()))
+    );
+    assert_eq!(
+        eval(&mut vm, call("min_by", vec![Eval::FnRef("len".to_string()), words()])),
+        Ok(Value::String("fig".to_string()))
+    );
+    assert_eq!(
+        eval(&mut vm, call("max_by", vec![Eval::FnRef("len".to_string()), Eval::Array(vec![])])),
+        Err(RuntimeError::invalid_argument("max_by", "array must not be empty"))
+    );
+}
+
+#[test]
+fn scan() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(vec![
+        BuiltInFunction::new("add".to_string(), 2, false, |vm, args| {
+            let lhs = vm.eval(args[0].clone())?.as_int();
+            let rhs = vm.eval(args[1].clone())?.as_int();
+            Ok(Some(Value::Int(lhs + rhs)))
+        }),
+    ]);
+
+    let items = (1..=3).map(Eval::Int).collect();
+    assert_eq!(
+        eval(&mut vm, call("scan", vec![Eval::FnRef("add".to_string()), Eval::Array(items), Eval::Int(0)])),
+        Ok(Value::Array(vec![Value::Int(1), Value::Int(3), Value::Int(6)]))
+    );
+}
+
+#[test]
+fn rename_field() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![
+            ("name".to_string(), string("qahl")),
+            ("size".to_string(), Eval::Int(3)),
+        ]),
+        Node::FnCall("rename_field".to_string(), vec![common::object(1), string("name"), string("title")]),
+    ]).unwrap();
+
+    let fields = &vm.objects[&1].fields;
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields["title"], Value::String("qahl".to_string()));
+    assert!(!fields.contains_key("name"));
+
+    assert_eq!(
+        vm.run(vec![Node::FnCall("rename_field".to_string(), vec![common::object(1), string("name"), string("label")])]),
+        Err(RuntimeError::invalid_argument("rename_field", "field name does not exist"))
+    );
+    assert_eq!(
+        vm.run(vec![Node::FnCall("rename_field".to_string(), vec![common::object(1), string("title"), string("size")])]),
+        Err(RuntimeError::invalid_argument("rename_field", "field size already exists"))
+    );
+}
+
+#[test]
+fn push_into_preallocated_array() {
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("arr".to_string(), call("array_with_capacity", vec![Eval::Int(10_000)])),
+        Node::Assign("i".to_string(), Eval::Int(0)),
+        Node::WhileLoop(Eval::Lt(var("i"), Box::new(Eval::Int(10_000))), vec![
+            Node::FnCall("push".to_string(), vec![string("arr"), Eval::VarRef("i".to_string())]),
+            Node::Assign("i".to_string(), Eval::Add(var("i"), Box::new(Eval::Int(1)))),
+        ]),
+    ]).unwrap();
+
+    match &vm.global_variables["arr"] {
+        Value::Array(items) => {
+            assert_eq!(items.len(), 10_000);
+            assert_eq!(items.capacity(), 10_000);
+            assert_eq!(items[9_999], Value::Int(9_999));
+        }
+        val => panic!("Expected array, got {:?}", val)
+    }
+
+    assert_eq!(vm.make_array(vec![Value::Int(1)]), Value::Array(vec![Value::Int(1)]));
+    assert_eq!(
+        vm.run(vec![Node::FnCall("push".to_string(), vec![string("i"), Eval::Int(1)])]),
+        Err(RuntimeError::argument_type("push", 0, "name of an array variable", &Value::Int(10_000)))
+    );
+}
+
+#[test]
+fn coalesce() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(
+        eval(&mut vm, call("coalesce", vec![Eval::Null, Eval::Null, Eval::Int(5), Eval::Int(9)])),
+        Ok(Value::Int(5))
+    );
+    assert_eq!(eval(&mut vm, call("coalesce", vec![Eval::Null])), Ok(Value::Null));
+    assert_eq!(eval(&mut vm, call("coalesce", vec![])), Ok(Value::Null));
+}
+
+#[test]
+fn fill_and_resize() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    assert_eq!(eval(&mut vm, call("fill", vec![Eval::Int(0), Eval::Int(3)])), Ok(ints(&[0, 0, 0])));
+    assert_eq!(
+        eval(&mut vm, call("resize", vec![Eval::Array(vec![Eval::Int(1), Eval::Int(2)]), Eval::Int(4), Eval::Int(9)])),
+        Ok(ints(&[1, 2, 9, 9]))
+    );
+    assert_eq!(
+        eval(&mut vm, call("resize", vec![Eval::Array(vec![Eval::Int(1), Eval::Int(2)]), Eval::Int(1), Eval::Int(9)])),
+        Ok(ints(&[1]))
+    );
+    assert_eq!(eval(&mut vm, call("fill", vec![Eval::Int(0), Eval::Int(-1)])), Err(RuntimeError::NegativeCount(-1)));
+}
+
+fn predicate_functions() -> Vec<BuiltInFunction> {
+    vec![
+        BuiltInFunction::new("is_big".to_string(), 1, false, |vm, args| {
+            Ok(Some(Value::Bool(vm.eval(args[0].clone())?.as_int() > 10)))
+        }),
+        // Counts its calls in `checked`, so tests can see where iteration stopped.
+        BuiltInFunction::new("is_small".to_string(), 1, false, |vm, args| {
+            let val = vm.eval(args[0].clone())?.as_int();
+            let checked = vm.global_variables.get("checked").map_or(0, |val| val.as_int());
+            vm.global_variables.insert("checked".to_string(), Value::Int(checked + 1));
+            Ok(Some(Value::Bool(val < 10)))
+        }),
+        BuiltInFunction::new("is_even".to_string(), 1, false, |vm, args| {
+            Ok(Some(Value::Bool(vm.eval(args[0].clone())?.as_int() % 2 == 0)))
+        }),
+        BuiltInFunction::new("is_positive".to_string(), 1, false, |vm, args| {
+            Ok(Some(Value::Bool(vm.eval(args[0].clone())?.as_int() > 0)))
+        }),
+    ]
+}
+
+#[test]
+fn find() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(predicate_functions());
+    let items = || Eval::Array(vec![Eval::Int(3), Eval::Int(12), Eval::Int(40)]);
+
+    assert_eq!(eval(&mut vm, call("find", vec![Eval::FnRef("is_big".to_string()), items()])), Ok(Value::Int(12)));
+    assert_eq!(
+        eval(&mut vm, call("find", vec![Eval::FnRef("is_big".to_string()), Eval::Array(vec![Eval::Int(1)])])),
+        Ok(Value::Null)
+    );
+}
+
+#[test]
+fn any_and_all_short_circuit() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(predicate_functions());
+    let items = || Eval::Array(vec![Eval::Int(3), Eval::Int(12), Eval::Int(4), Eval::Int(5)]);
+
+    assert_eq!(eval(&mut vm, call("all", vec![Eval::FnRef("is_small".to_string()), items()])), Ok(Value::Bool(false)));
+    assert_eq!(vm.global_variables["checked"], Value::Int(2));
+
+    assert_eq!(eval(&mut vm, call("any", vec![Eval::FnRef("is_big".to_string()), items()])), Ok(Value::Bool(true)));
+    assert_eq!(eval(&mut vm, call("any", vec![Eval::FnRef("is_big".to_string()), Eval::Array(vec![])])), Ok(Value::Bool(false)));
+    assert_eq!(eval(&mut vm, call("all", vec![Eval::FnRef("is_big".to_string()), Eval::Array(vec![])])), Ok(Value::Bool(true)));
+}
+
+#[test]
+fn entries_round_trip() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![
+            ("b".to_string(), Eval::Int(2)),
+            ("a".to_string(), string("one")),
+        ]),
+    ]).unwrap();
+
+    assert_eq!(
+        eval(&mut vm, call("entries", vec![common::object(1)])),
+        Ok(Value::Array(vec![
+            Value::Array(vec![Value::String("a".to_string()), Value::String("one".to_string())]),
+            Value::Array(vec![Value::String("b".to_string()), Value::Int(2)]),
+        ]))
+    );
+
+    let id = match eval(&mut vm, call("from_entries", vec![call("entries", vec![common::object(1)])])) {
+        Ok(Value::Object(id)) => id,
+        res => panic!("Expected object, got {:?}", res)
+    };
+    assert_ne!(id, 1);
+    let fields = &vm.objects[&id].fields;
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields["a"], Value::String("one".to_string()));
+    assert_eq!(fields["b"], Value::Int(2));
+
+    assert_eq!(
+        eval(&mut vm, call("from_entries", vec![Eval::Array(vec![Eval::Array(vec![Eval::Int(1), Eval::Int(2)])])])),
+        Err(RuntimeError::invalid_argument("from_entries", "entries must be [string, value] pairs"))
+    );
+}
+
+#[test]
+fn float_precision_applies_to_print_and_str() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let output = common::SharedBuffer::default();
+    vm.set_output(output.clone());
+    vm.float_precision = Some(3);
+
+    let sum = Eval::Add(Box::new(Eval::Float(0.1)), Box::new(Eval::Float(0.2)));
+    vm.run(vec![
+        Node::FnCall("println".to_string(), vec![sum.clone()]),
+        Node::FnCall("println".to_string(), vec![Eval::Array(vec![Eval::Float(2.0)])]),
+    ]).unwrap();
+
+    assert_eq!(output.contents(), "0.300\n[2.000]\n");
+    assert_eq!(eval(&mut vm, call("str", vec![sum])), Ok(Value::String("0.300".to_string())));
+}
+
+#[test]
+fn display_uses_float_precision() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.float_precision = Some(2);
+
+    assert_eq!(format!("{}", vm.display(&Value::Float(1.5))), "1.50");
+    assert_eq!(vm.display(&Value::Array(vec![Value::Float(2.0)])).to_string(), "[2.00]");
+
+    vm.float_precision = None;
+    assert_eq!(vm.display(&Value::Float(1.5)).to_string(), "1.5");
+}
+
+#[test]
+fn functions_lists_builtin_and_defined_names() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("my_func".to_string(), vec![], vec![], false),
+    ]);
+
+    let names = match eval(&mut vm, call("functions", vec![])) {
+        Ok(Value::Array(names)) => names,
+        res => panic!("Expected array, got {:?}", res)
+    };
+    assert!(names.contains(&Value::String("print".to_string())));
+    assert!(names.contains(&Value::String("my_func".to_string())));
+    assert!(names.contains(&Value::String("functions".to_string())));
+
+    let mut sorted = names.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(names, sorted);
+}
+
+#[test]
+fn vm_stats() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![]),
+        Node::CreateObject(Eval::Int(2), vec![]),
+        Node::Assign("a".to_string(), Eval::Int(1)),
+        Node::Assign("b".to_string(), Eval::Int(2)),
+        Node::Assign("stats".to_string(), call("vm_stats", vec![])),
+    ]).unwrap();
+
+    let id = match vm.global_variables["stats"] {
+        Value::Object(id) => id,
+        ref val => panic!("Expected object, got {:?}", val)
+    };
+    let fields = &vm.objects[&id].fields;
+    assert_eq!(fields["object_count"], Value::Int(2));
+    assert_eq!(fields["global_count"], Value::Int(2));
+    assert_eq!(fields["scope_depth"], Value::Int(0));
+    assert_eq!(fields["function_count"], Value::Int(vm.functions.len() as i32));
+}
+
+#[test]
+fn nan_and_infinity() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let nan = || call("nan", vec![]);
+    let inf = || call("inf", vec![]);
+
+    assert_eq!(eval(&mut vm, call("is_nan", vec![nan()])), Ok(Value::Bool(true)));
+    assert_eq!(eval(&mut vm, call("is_nan", vec![Eval::Int(1)])), Ok(Value::Bool(false)));
+    assert_eq!(eval(&mut vm, Eval::Eq(Box::new(nan()), Box::new(nan()))), Ok(Value::Bool(false)));
+    assert_eq!(eval(&mut vm, Eval::Ne(Box::new(nan()), Box::new(nan()))), Ok(Value::Bool(true)));
+
+    assert_eq!(eval(&mut vm, call("is_finite", vec![inf()])), Ok(Value::Bool(false)));
+    assert_eq!(eval(&mut vm, call("is_finite", vec![nan()])), Ok(Value::Bool(false)));
+    assert_eq!(eval(&mut vm, call("is_finite", vec![Eval::Float(1.5)])), Ok(Value::Bool(true)));
+    assert_eq!(eval(&mut vm, Eval::Gt(Box::new(inf()), Box::new(Eval::Float(f32::MAX)))), Ok(Value::Bool(true)));
+}
+
+#[test]
+fn format_placeholders() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    assert_eq!(
+        eval(&mut vm, call("format", vec![string("{1} {0} {1}"), string("a"), string("b")])),
+        Ok(Value::String("b a b".to_string()))
+    );
+    assert_eq!(
+        eval(&mut vm, call("format", vec![string("{} + {} = {{{}}}"), Eval::Int(1), Eval::Float(2.0), Eval::Int(3)])),
+        Ok(Value::String("1 + 2.0 = {3}".to_string()))
+    );
+    assert_eq!(
+        eval(&mut vm, call("format", vec![string("{0} {}"), string("a"), string("b")])),
+        Err(RuntimeError::invalid_argument("format", "cannot mix {} and {N} placeholders"))
+    );
+    assert_eq!(
+        eval(&mut vm, call("format", vec![string("{2}"), string("a")])),
+        Err(RuntimeError::invalid_argument("format", "no argument for placeholder 2"))
+    );
+}
+
+#[test]
+fn split_and_join_lines() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let lines = || Value::Array(vec![
+        Value::String("first".to_string()),
+        Value::String("".to_string()),
+        Value::String("third".to_string()),
+    ]);
+
+    assert_eq!(eval(&mut vm, call("split_lines", vec![string("first\n\nthird\n")])), Ok(lines()));
+    assert_eq!(eval(&mut vm, call("split_lines", vec![string("first\r\n\r\nthird")])), Ok(lines()));
+    assert_eq!(eval(&mut vm, call("split_lines", vec![string("")])), Ok(Value::Array(vec![])));
+
+    assert_eq!(
+        eval(&mut vm, call("join_lines", vec![call("split_lines", vec![string("first\r\n\r\nthird\n")])])),
+        Ok(Value::String("first\n\nthird".to_string()))
+    );
+}
+
+#[test]
+fn deep_equals() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("n".to_string(), Eval::Int(1))]),
+        Node::CreateObject(Eval::Int(2), vec![("n".to_string(), Eval::Int(1))]),
+        Node::CreateObject(Eval::Int(3), vec![("child".to_string(), common::object(1)), ("tags".to_string(), Eval::Array(vec![string("a")]))]),
+        Node::CreateObject(Eval::Int(4), vec![("child".to_string(), common::object(2)), ("tags".to_string(), Eval::Array(vec![string("a")]))]),
+        Node::CreateObject(Eval::Int(5), vec![("child".to_string(), common::object(2)), ("tags".to_string(), Eval::Array(vec![]))]),
+        // Two cycles of the same shape.
+        Node::SetMember(Eval::Int(1), "next".to_string(), common::object(1)),
+        Node::SetMember(Eval::Int(2), "next".to_string(), common::object(2)),
+    ]).unwrap();
+
+    let deep_equals = |vm: &mut VirtualMachine, l: i32, r: i32| {
+        eval(vm, call("deep_equals", vec![common::object(l), common::object(r)]))
+    };
+    assert_eq!(deep_equals(&mut vm, 3, 4), Ok(Value::Bool(true)));
+    assert_eq!(deep_equals(&mut vm, 1, 2), Ok(Value::Bool(true)));
+    assert_eq!(deep_equals(&mut vm, 3, 5), Ok(Value::Bool(false)));
+    assert_eq!(deep_equals(&mut vm, 1, 3), Ok(Value::Bool(false)));
+    assert_eq!(
+        eval(&mut vm, call("deep_equals", vec![Eval::Array(vec![common::object(1)]), Eval::Array(vec![common::object(2)])])),
+        Ok(Value::Bool(true))
+    );
+}
+
+#[test]
+fn argmin_and_argmax() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    assert_eq!(eval(&mut vm, call("argmax", vec![eval_ints(&[3, 7, 2])])), Ok(Value::Int(1)));
+    assert_eq!(eval(&mut vm, call("argmin", vec![eval_ints(&[5, 1, 1])])), Ok(Value::Int(1)));
+    assert_eq!(
+        eval(&mut vm, call("argmax", vec![Eval::Array(vec![Eval::Int(2), Eval::Float(2.5)])])),
+        Ok(Value::Int(1))
+    );
+    assert_eq!(
+        eval(&mut vm, call("argmin", vec![eval_ints(&[])])),
+        Err(RuntimeError::invalid_argument("argmin", "the array is empty"))
+    );
+    assert_eq!(
+        eval(&mut vm, call("argmax", vec![Eval::Array(vec![Eval::Int(1), string("a")])])),
+        Err(RuntimeError::argument_type("argmax", 0, "number", &Value::String("a".to_string())))
+    );
+}
+
+#[test]
+fn bytes() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    assert_eq!(eval(&mut vm, call("to_byte", vec![Eval::Int(255)])), Ok(Value::Int(255)));
+    assert_eq!(
+        eval(&mut vm, call("to_byte", vec![Eval::Int(300)])),
+        Err(RuntimeError::invalid_argument("to_byte", "300 does not fit in a byte"))
+    );
+    assert_eq!(
+        eval(&mut vm, call("byte_array", vec![string("AB")])),
+        Ok(Value::Array(vec![Value::Int(65), Value::Int(66)]))
+    );
+    assert_eq!(
+        eval(&mut vm, call("byte_array", vec![string("é")])),
+        Ok(Value::Array(vec![Value::Int(0xc3), Value::Int(0xa9)]))
+    );
+}
+
+#[test]
+fn print_table() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let output = common::SharedBuffer::default();
+    vm.set_output(output.clone());
+
+    let row = |cells: Vec<Eval>| Eval::Array(cells);
+    vm.run(vec![
+        Node::FnCall("print_table".to_string(), vec![Eval::Array(vec![
+            row(vec![string("name"), string("qty"), string("price")]),
+            row(vec![string("apple"), Eval::Int(3), Eval::Float(1.5)]),
+            row(vec![string("fig"), Eval::Int(12), Eval::Float(10.0)]),
+        ])]),
+    ]).unwrap();
+
+    assert_eq!(output.contents(), "\
+name   qty  price
+apple  3    1.5
+fig    12   10.0
+");
+
+    assert_eq!(
+        eval(&mut vm, call("print_table", vec![Eval::Array(vec![row(vec![Eval::Int(1)]), row(vec![])])])),
+        Err(RuntimeError::invalid_argument("print_table", "all rows must have the same length"))
+    );
+}
+
+#[test]
+fn get_with_default() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let items = || Eval::Array(vec![Eval::Int(10), Eval::Int(20), Eval::Int(30)]);
+    let get = |vm: &mut VirtualMachine, index: i32| eval(vm, call("get", vec![items(), Eval::Int(index), Eval::Null]));
+
+    assert_eq!(get(&mut vm, 1), Ok(Value::Int(20)));
+    assert_eq!(get(&mut vm, 3), Ok(Value::Null));
+    assert_eq!(get(&mut vm, -1), Ok(Value::Int(30)));
+    assert_eq!(get(&mut vm, -3), Ok(Value::Int(10)));
+    assert_eq!(get(&mut vm, -4), Ok(Value::Null));
+}
+
+#[test]
+fn pluck() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("name".to_string(), string("ada"))]),
+        Node::CreateObject(Eval::Int(2), vec![("name".to_string(), string("bob")), ("age".to_string(), Eval::Int(3))]),
+        Node::CreateObject(Eval::Int(3), vec![("name".to_string(), string("cy"))]),
+    ]).unwrap();
+    let objects = |ids: &[i32]| Eval::Array(ids.iter().map(|id| common::object(*id)).collect());
+
+    assert_eq!(
+        eval(&mut vm, call("pluck", vec![objects(&[1, 2, 3]), string("name")])),
+        Ok(Value::Array(vec![
+            Value::String("ada".to_string()),
+            Value::String("bob".to_string()),
+            Value::String("cy".to_string()),
+        ]))
+    );
+    assert_eq!(
+        eval(&mut vm, call("pluck", vec![objects(&[2, 3]), string("age")])),
+        Err(RuntimeError::invalid_argument("pluck", "object 3 has no field age"))
+    );
+    assert_eq!(
+        eval(&mut vm, call("pluck", vec![Eval::Array(vec![Eval::Int(1)]), string("name")])),
+        Err(RuntimeError::argument_type("pluck", 0, "array of objects", &Value::Int(1)))
+    );
+}
+
+#[test]
+fn apply_spreads_arguments() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("hypot_sq".to_string(), vec!["a".to_string(), "b".to_string()], vec![
+            Node::Return(Eval::Add(
+                Box::new(Eval::Mul(Box::new(Eval::VarRef("a".to_string())), Box::new(Eval::VarRef("a".to_string())))),
+                Box::new(Eval::Mul(Box::new(Eval::VarRef("b".to_string())), Box::new(Eval::VarRef("b".to_string())))),
+            )),
+        ], false),
+    ]);
+    let fn_ref = || Eval::FnRef("hypot_sq".to_string());
+
+    assert_eq!(
+        eval(&mut vm, call("apply", vec![fn_ref(), Eval::Array(vec![Eval::Int(3), Eval::Int(4)])])),
+        Ok(Value::Int(25))
+    );
+    assert_eq!(
+        eval(&mut vm, call("apply", vec![fn_ref(), Eval::Array(vec![Eval::Int(3)])])),
+        Err(RuntimeError::invalid_argument("apply", "hypot_sq cannot take 1 arguments"))
+    );
+    assert_eq!(
+        eval(&mut vm, call("apply", vec![Eval::FnRef("coalesce".to_string()), Eval::Array(vec![Eval::Null, Eval::Int(2)])])),
+        Ok(Value::Int(2))
+    );
+}
+
+#[test]
+fn apply_running_function() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("recurse".to_string(), vec!["f".to_string()], vec![
+            Node::Return(call("apply", vec![Eval::VarRef("f".to_string()), Eval::Array(vec![Eval::VarRef("f".to_string())])])),
+        ], false),
+    ]);
+    vm.global_variables.insert("me".to_string(), Value::Function("recurse".to_string()));
+
+    assert_eq!(
+        eval(&mut vm, call("recurse", vec![Eval::VarRef("me".to_string())])),
+        Err(RuntimeError::FunctionUnavailable("recurse".to_string()))
+    );
+}
+
+#[test]
+fn sleep() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    let start = std::time::Instant::now();
+    assert_eq!(eval(&mut vm, call("sleep", vec![Eval::Int(50)])), Ok(Value::Unit));
+    assert!(start.elapsed() >= std::time::Duration::from_millis(50));
+
+    assert_eq!(
+        eval(&mut vm, call("sleep", vec![Eval::Int(-1)])),
+        Err(RuntimeError::invalid_argument("sleep", "the duration must not be negative"))
+    );
+
+    let start = std::time::Instant::now();
+    let res = vm.run_with_timeout(vec![
+        Node::FnCall("sleep".to_string(), vec![Eval::Int(60_000)]),
+    ], std::time::Duration::from_millis(50));
+    assert_eq!(res, Err(RuntimeError::Timeout));
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn env() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    std::env::set_var("QAHLVM_ENV_TEST", "on");
+
+    assert_eq!(eval(&mut vm, call("env", vec![string("QAHLVM_ENV_TEST")])), Ok(Value::String("on".to_string())));
+    assert_eq!(eval(&mut vm, call("env", vec![string("QAHLVM_ENV_UNSET")])), Ok(Value::Null));
+    assert_eq!(
+        eval(&mut vm, call("env", vec![string("QAHLVM_ENV_UNSET"), string("off")])),
+        Ok(Value::String("off".to_string()))
+    );
+
+    vm.sandboxed = true;
+    assert_eq!(
+        eval(&mut vm, call("env", vec![string("QAHLVM_ENV_TEST")])),
+        Err(RuntimeError::Sandboxed("env"))
+    );
+}
+
+#[test]
+fn program_args() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(eval(&mut vm, call("args", vec![])), Ok(Value::Array(vec![])));
+
+    vm.set_args(vec!["input.txt".to_string(), "--verbose".to_string(), "3".to_string()]);
+    assert_eq!(
+        eval(&mut vm, call("args", vec![])),
+        Ok(Value::Array(vec![
+            Value::String("input.txt".to_string()),
+            Value::String("--verbose".to_string()),
+            Value::String("3".to_string()),
+        ]))
+    );
+}
+
+#[test]
+fn hash_is_structural() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("a".to_string(), Eval::Int(1)), ("b".to_string(), Eval::Float(2.0))]),
+        Node::CreateObject(Eval::Int(2), vec![("b".to_string(), Eval::Float(2.0)), ("a".to_string(), Eval::Int(1))]),
+    ]).unwrap();
+    let mut hash = |val: Eval| eval(&mut vm, call("hash", vec![val])).unwrap();
+
+    assert_eq!(hash(eval_ints(&[1, 2])), hash(eval_ints(&[1, 2])));
+    assert_ne!(hash(eval_ints(&[1, 2])), hash(eval_ints(&[2, 1])));
+    assert_eq!(hash(common::object(1)), hash(common::object(2)));
+    assert_ne!(hash(Eval::Int(1)), hash(Eval::Float(1.0)));
+    assert_ne!(
+        hash(Eval::Array(vec![string("ab")])),
+        hash(Eval::Array(vec![string("a"), string("b")]))
+    );
+}
+
+#[test]
+fn float_bits_round_trip() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    assert_eq!(eval(&mut vm, call("float_to_bits", vec![Eval::Float(1.5)])), Ok(Value::Int(0x3fc00000)));
+    assert_eq!(
+        eval(&mut vm, call("bits_to_float", vec![call("float_to_bits", vec![Eval::Float(1.5)])])),
+        Ok(Value::Float(1.5))
+    );
+    assert_eq!(eval(&mut vm, call("float_to_bits", vec![Eval::Float(-2.0)])), Ok(Value::Int(0xc0000000_u32 as i32)));
+    assert_eq!(
+        eval(&mut vm, call("bits_to_float", vec![call("float_to_bits", vec![Eval::Float(-2.0)])])),
+        Ok(Value::Float(-2.0))
+    );
+}
+
+#[test]
+fn partition() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(predicate_functions());
+
+    assert_eq!(
+        eval(&mut vm, call("partition", vec![
+            Eval::FnRef("is_even".to_string()),
+            Eval::Array((1..=6).map(Eval::Int).collect()),
+        ])),
+        Ok(Value::Array(vec![ints(&[2, 4, 6]), ints(&[1, 3, 5])]))
+    );
+}
+
+#[test]
+fn take_and_drop() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_rust_functions(predicate_functions());
+    let is_positive = || Eval::FnRef("is_positive".to_string());
+
+    assert_eq!(eval(&mut vm, call("take", vec![eval_ints(&[1, 2, 3, 4]), Eval::Int(2)])), Ok(ints(&[1, 2])));
+    assert_eq!(eval(&mut vm, call("take", vec![eval_ints(&[1, 2]), Eval::Int(5)])), Ok(ints(&[1, 2])));
+    assert_eq!(eval(&mut vm, call("drop", vec![eval_ints(&[1, 2, 3, 4]), Eval::Int(3)])), Ok(ints(&[4])));
+    assert_eq!(eval(&mut vm, call("drop", vec![eval_ints(&[1, 2]), Eval::Int(5)])), Ok(ints(&[])));
+    assert_eq!(
+        eval(&mut vm, call("take", vec![eval_ints(&[1]), Eval::Int(-1)])),
+        Err(RuntimeError::NegativeCount(-1))
+    );
+
+    assert_eq!(eval(&mut vm, call("take_while", vec![is_positive(), eval_ints(&[1, 2, -1, 3])])), Ok(ints(&[1, 2])));
+    assert_eq!(eval(&mut vm, call("drop_while", vec![is_positive(), eval_ints(&[1, 2, -1, 3])])), Ok(ints(&[-1, 3])));
+    assert_eq!(eval(&mut vm, call("drop_while", vec![is_positive(), eval_ints(&[1, 2])])), Ok(ints(&[])));
+}
+
+#[test]
+fn chunk_and_windows() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    assert_eq!(
+        eval(&mut vm, call("chunk", vec![eval_ints(&[1, 2, 3, 4, 5]), Eval::Int(2)])),
+        Ok(Value::Array(vec![ints(&[1, 2]), ints(&[3, 4]), ints(&[5])]))
+    );
+    assert_eq!(
+        eval(&mut vm, call("windows", vec![eval_ints(&[1, 2, 3]), Eval::Int(2)])),
+        Ok(Value::Array(vec![ints(&[1, 2]), ints(&[2, 3])]))
+    );
+    assert_eq!(eval(&mut vm, call("windows", vec![eval_ints(&[1]), Eval::Int(2)])), Ok(ints(&[])));
+    assert_eq!(
+        eval(&mut vm, call("chunk", vec![eval_ints(&[1]), Eval::Int(0)])),
+        Err(RuntimeError::invalid_argument("chunk", "the size must be positive"))
+    );
+}
+
+#[test]
+fn justify_multibyte_strings() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let mut justify = |function: &str, width: i32, fill: Option<Eval>| {
+        let mut args = vec![string("héé"), Eval::Int(width)];
+        args.extend(fill);
+        eval(&mut vm, call(function, args))
+    };
+
+    assert_eq!(justify("ljust", 6, None), Ok(Value::String("héé   ".to_string())));
+    assert_eq!(justify("rjust", 6, Some(string("·"))), Ok(Value::String("···héé".to_string())));
+    assert_eq!(justify("center", 6, Some(Eval::Char('*'))), Ok(Value::String("*héé**".to_string())));
+    assert_eq!(justify("center", 2, None), Ok(Value::String("héé".to_string())));
+    assert_eq!(
+        justify("ljust", 6, Some(string("ab"))),
+        Err(RuntimeError::argument_type("ljust", 2, "single char", &Value::String("ab".to_string())))
+    );
+}
+
+#[test]
+fn parse_numbers() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let mut parse = |function: &str, text: &str| eval(&mut vm, call(function, vec![string(text)]));
+
+    assert_eq!(parse("int", "  -42 "), Ok(Value::Int(-42)));
+    assert_eq!(parse("int", "+7\n"), Ok(Value::Int(7)));
+    assert_eq!(parse("float", "+3.5"), Ok(Value::Float(3.5)));
+    assert_eq!(parse("float", " -1e3"), Ok(Value::Float(-1000.0)));
+    assert_eq!(parse("float", "2.5E-1"), Ok(Value::Float(0.25)));
+    assert_eq!(parse("int", "42abc"), Err(RuntimeError::InvalidCast { from: "string", to: "int" }));
+    assert_eq!(parse("float", "3.5.1"), Err(RuntimeError::InvalidCast { from: "string", to: "float" }));
+    assert_eq!(parse("int", "- 4"), Err(RuntimeError::InvalidCast { from: "string", to: "int" }));
+    for text in ["nan", "inf", "-infinity", "1e50"] {
+        assert_eq!(parse("float", text), Err(RuntimeError::InvalidCast { from: "string", to: "float" }));
+    }
+}
+
+#[test]
+fn shuffle() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let numbers = Eval::Array((0..10).map(Eval::Int).collect());
+    let mut shuffled = |seed: i32| {
+        vm.run(vec![Node::FnCall("seed".to_string(), vec![Eval::Int(seed)])]).unwrap();
+        match eval(&mut vm, call("shuffle", vec![numbers.clone()])) {
+            Ok(Value::Array(items)) => items,
+            res => panic!("shuffle returned {:?}", res)
+        }
+    };
+
+    let first = shuffled(42);
+    assert_eq!(shuffled(42), first);
+    assert_ne!(first, (0..10).map(Value::Int).collect::<Vec<_>>());
+
+    let mut sorted = first.iter()
+        .map(|item| match item { Value::Int(val) => *val, _ => unreachable!() })
+        .collect::<Vec<_>>();
+    sorted.sort();
+    assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+
+    assert_eq!(eval(&mut vm, call("shuffle", vec![Eval::Array(vec![])])), Ok(Value::Array(vec![])));
+}
+
+#[test]
+fn choice_and_sample() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let letters = Eval::Array(["a", "b", "c", "d", "e"].into_iter().map(string).collect());
+    let mut seeded = |seed: i32, function: &str, mut args: Vec<Eval>| {
+        vm.run(vec![Node::FnCall("seed".to_string(), vec![Eval::Int(seed)])]).unwrap();
+        args.insert(0, letters.clone());
+        eval(&mut vm, call(function, args))
+    };
+
+    let picked = seeded(7, "choice", vec![]);
+    assert_eq!(seeded(7, "choice", vec![]), picked);
+    assert!(matches!(picked, Ok(Value::String(_))));
+
+    let sampled = seeded(7, "sample", vec![Eval::Int(3)]);
+    assert_eq!(seeded(7, "sample", vec![Eval::Int(3)]), sampled);
+    let Ok(Value::Array(sampled)) = sampled else { panic!("sample returned {:?}", sampled) };
+    assert_eq!(sampled.len(), 3);
+    assert!(sampled.iter().enumerate().all(|(i, item)| !sampled[..i].contains(item)));
+
+    assert_eq!(
+        seeded(7, "sample", vec![Eval::Int(6)]),
+        Err(RuntimeError::invalid_argument("sample", "the count must be between 0 and the array length"))
+    );
+    assert_eq!(
+        eval(&mut vm, call("choice", vec![Eval::Array(vec![])])),
+        Err(RuntimeError::invalid_argument("choice", "the array must not be empty"))
+    );
+}
+
+#[test]
+fn deep_copy() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("value".to_string(), Eval::Int(1))]),
+    ]).unwrap();
+
+    let original = Eval::Array(vec![common::object(1), Eval::Array(vec![common::object(1), Eval::Int(5)])]);
+    let copy = eval(&mut vm, call("deep_copy", vec![original])).unwrap();
+    let Value::Array(items) = &copy else { panic!("deep_copy returned {:?}", copy) };
+    let Value::Object(id) = items[0] else { panic!("expected an object, found {:?}", items[0]) };
+    assert_ne!(id, 1);
+    assert_eq!(items[1], Value::Array(vec![Value::Object(id), Value::Int(5)]));
+
+    vm.run(vec![Node::SetMember(Eval::Int(id as i32), "value".to_string(), Eval::Int(2))]).unwrap();
+    assert_eq!(vm.objects[&id].fields["value"], Value::Int(2));
+    assert_eq!(vm.objects[&1].fields["value"], Value::Int(1));
+}
+
+#[test]
+fn frequencies() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let tally = |vm: &mut VirtualMachine, items: Vec<Eval>| {
+        match eval(vm, call("frequencies", vec![Eval::Array(items)])) {
+            Ok(Value::Object(id)) => vm.objects[&id].fields.clone(),
+            res => panic!("frequencies returned {:?}", res)
+        }
+    };
+
+    let counts = tally(&mut vm, vec![string("a"), string("b"), string("a")]);
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts["a"], Value::Int(2));
+    assert_eq!(counts["b"], Value::Int(1));
+
+    let counts = tally(&mut vm, vec![Eval::Int(1), Eval::Bool(true), Eval::Int(1)]);
+    assert_eq!(counts["1"], Value::Int(2));
+    assert_eq!(counts["true"], Value::Int(1));
+    assert!(tally(&mut vm, vec![]).is_empty());
+}
+
+#[test]
+fn flatten_object() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(3), vec![("c".to_string(), Eval::Int(1))]),
+        Node::CreateObject(Eval::Int(2), vec![
+            ("b".to_string(), common::object(3)),
+            ("list".to_string(), Eval::Array(vec![Eval::Int(2)])),
+        ]),
+        Node::CreateObject(Eval::Int(1), vec![
+            ("a".to_string(), common::object(2)),
+            ("top".to_string(), Eval::Bool(true)),
+        ]),
+    ]).unwrap();
+
+    let flat = eval(&mut vm, call("flatten_object", vec![common::object(1)]));
+    let Ok(Value::Object(id)) = flat else { panic!("flatten_object returned {:?}", flat) };
+    let fields = &vm.objects[&id].fields;
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields["a.b.c"], Value::Int(1));
+    assert_eq!(fields["a.list"], Value::Array(vec![Value::Int(2)]));
+    assert_eq!(fields["top"], Value::Bool(true));
+
+    vm.run(vec![Node::SetMember(Eval::Int(3), "back".to_string(), common::object(1))]).unwrap();
+    assert_eq!(
+        eval(&mut vm, call("flatten_object", vec![common::object(1)])),
+        Err(RuntimeError::invalid_argument("flatten_object", "the object must not contain cycles"))
+    );
+}
+
+#[test]
+fn array_object_round_trip() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let items = Eval::Array(vec![string("a"), Eval::Int(2), Eval::Bool(false)]);
+
+    let converted = eval(&mut vm, call("array_to_object", vec![items])).unwrap();
+    let Value::Object(id) = converted else { panic!("array_to_object returned {:?}", converted) };
+    assert_eq!(vm.objects[&id].fields["1"], Value::Int(2));
+
+    assert_eq!(
+        eval(&mut vm, call("object_to_array", vec![common::object(id as i32)])),
+        Ok(Value::Array(vec![Value::String("a".to_string()), Value::Int(2), Value::Bool(false)]))
+    );
+
+    vm.run(vec![Node::CreateObject(Eval::Int(9), vec![
+        ("0".to_string(), Eval::Int(1)),
+        ("2".to_string(), Eval::Int(3)),
+    ])]).unwrap();
+    assert_eq!(
+        eval(&mut vm, call("object_to_array", vec![common::object(9)])),
+        Err(RuntimeError::invalid_argument("object_to_array", "the keys must be contiguous indices from 0"))
+    );
+}
@@ -1,3 +1,66 @@
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use qahlvm::ast::{Eval, Node};
+use qahlvm::error::RuntimeError;
+use qahlvm::vm::{Value, VirtualMachine};
+
 pub fn setup() {
     // setup code specific to your library's tests would go here
-}
\ No newline at end of file
+}
+
+pub fn object(id: i32) -> Eval {
+    Eval::Object(Box::new(Eval::Int(id)))
+}
+
+pub fn string(val: &str) -> Eval {
+    Eval::String(val.to_string())
+}
+
+/// A variable reference, boxed to be used as an operand.
+pub fn var(name: &str) -> Box<Eval> {
+    Box::new(Eval::VarRef(name.to_string()))
+}
+
+/// An array literal of ints.
+pub fn eval_ints(vals: &[i32]) -> Eval {
+    Eval::Array(vals.iter().map(|val| Eval::Int(*val)).collect())
+}
+
+/// The array value an `eval_ints` literal evaluates to.
+pub fn ints(vals: &[i32]) -> Value {
+    Value::Array(vals.iter().map(|val| Value::Int(*val)).collect())
+}
+
+pub fn call(name: &str, args: Vec<Eval>) -> Eval {
+    Eval::FnCall(name.to_string(), args)
+}
+
+/// Runs a single expression through the VM and hands back its value.
+pub fn eval(vm: &mut VirtualMachine, val: Eval) -> Result<Value, RuntimeError> {
+    vm.run(vec![Node::Assign("res".to_string(), val)])?;
+    Ok(vm.global_variables["res"].clone())
+}
+
+/// A writer that can be handed to the VM while the test keeps a handle to read it back.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::iter::zip;
 use qahlvm::ast::*;
+use qahlvm::error::*;
 use qahlvm::vm::*;
 
 mod common;
@@ -11,10 +12,36 @@ fn int() {
     assert_eq!(val.as_int(), 1);
 }
 
+#[test]
+fn int_from_hex() {
+    assert_eq!(Eval::int_from_hex("ff").unwrap().as_int(), 255);
+    assert_eq!(Eval::int_from_hex("-0x1F").unwrap().as_int(), -31);
+    assert_eq!(
+        Eval::int_from_hex("fg").unwrap_err(),
+        RuntimeError::InvalidLiteral { literal: "fg".to_string(), radix: 16 }
+    );
+    for digits in ["0x-5", "+ff", "-+ff", "--5", "0x", "-"] {
+        assert!(Eval::int_from_hex(digits).is_err(), "{} parsed", digits);
+    }
+}
+
+#[test]
+fn int_from_bin() {
+    assert_eq!(Eval::int_from_bin("1010").unwrap().as_int(), 10);
+    assert_eq!(Eval::int_from_bin("0b11").unwrap().as_int(), 3);
+    assert_eq!(
+        Eval::int_from_bin("102").unwrap_err(),
+        RuntimeError::InvalidLiteral { literal: "102".to_string(), radix: 2 }
+    );
+    assert!(Eval::int_from_bin("").is_err());
+    assert!(Eval::int_from_bin("0b-1").is_err());
+    assert!(Eval::int_from_bin("+1").is_err());
+}
+
 #[test]
 fn bool() {
     let val = Eval::Bool(true);
-    assert_eq!(val.as_bool(), true);
+    assert!(val.as_bool());
 }
 
 #[test]
@@ -41,7 +68,7 @@ fn array() {
 #[test]
 fn is_an_operator() {
     let val = Eval::Add(Box::new(Eval::Int(1)), Box::new(Eval::Int(2)));
-    assert_eq!(val.is_an_operator(), true);
+    assert!(val.is_an_operator());
 }
 
 #[test]
@@ -96,6 +123,6 @@ fn main_test(){
         Node::FnCall("println".to_string(), vec![Eval::FnCall("test".to_string(), vec![])])
     ];
 
-    vm.run(instructions);
+    vm.run(instructions).unwrap();
     println!("Done");
 }
\ No newline at end of file
@@ -1,5 +1,3 @@
-use qahlvm;
-
 mod common;
 
 #[test]
@@ -0,0 +1,124 @@
+use qahlvm::ast::*;
+use qahlvm::error::*;
+use qahlvm::json::from_json;
+use qahlvm::vm::*;
+
+mod common;
+
+fn to_json(val: Eval) -> Eval {
+    Eval::FnCall("to_json".to_string(), vec![val])
+}
+
+#[test]
+fn to_json_mixed_array() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("json".to_string(), to_json(Eval::Array(vec![
+            Eval::Int(1),
+            Eval::Float(2.5),
+            Eval::Bool(true),
+            Eval::String("a \"quoted\"\nline".to_string()),
+            Eval::Null,
+            Eval::Array(vec![]),
+        ]))),
+    ]).unwrap();
+
+    assert_eq!(
+        vm.global_variables["json"],
+        Value::String(r#"[1,2.5,true,"a \"quoted\"\nline",null,[]]"#.to_string())
+    );
+}
+
+#[test]
+fn to_json_nested_object() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("x".to_string(), Eval::Float(1.0))]),
+        Node::CreateObject(Eval::Int(2), vec![
+            ("name".to_string(), Eval::String("point".to_string())),
+            ("inner".to_string(), common::object(1)),
+            ("tags".to_string(), Eval::Array(vec![Eval::Int(1), Eval::Int(2)])),
+        ]),
+        Node::Assign("json".to_string(), to_json(common::object(2))),
+    ]).unwrap();
+
+    assert_eq!(
+        vm.global_variables["json"],
+        Value::String(r#"{"inner":{"x":1.0},"name":"point","tags":[1,2]}"#.to_string())
+    );
+}
+
+#[test]
+fn to_json_cycle() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let err = vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![]),
+        Node::SetMember(Eval::Int(1), "self".to_string(), common::object(1)),
+        Node::Assign("json".to_string(), to_json(common::object(1))),
+    ]).unwrap_err();
+
+    assert_eq!(err, RuntimeError::CyclicObject(1));
+}
+
+#[test]
+fn from_json_round_trip() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("x".to_string(), Eval::Float(1.5))]),
+        Node::CreateObject(Eval::Int(2), vec![
+            ("name".to_string(), Eval::String("point".to_string())),
+            ("inner".to_string(), common::object(1)),
+            ("items".to_string(), Eval::Array(vec![Eval::Int(1), Eval::Null, Eval::Bool(false)])),
+        ]),
+        Node::Assign("copy".to_string(), Eval::FnCall("from_json".to_string(), vec![to_json(common::object(2))])),
+        Node::Assign("original_json".to_string(), to_json(common::object(2))),
+        Node::Assign("copy_json".to_string(), to_json(Eval::VarRef("copy".to_string()))),
+    ]).unwrap();
+
+    let copy = match vm.global_variables["copy"] {
+        Value::Object(id) => id,
+        ref val => panic!("Expected object, got {:?}", val)
+    };
+    assert_ne!(copy, 2);
+    assert_eq!(vm.objects[&copy].fields["name"], Value::String("point".to_string()));
+    assert_eq!(vm.global_variables["original_json"], vm.global_variables["copy_json"]);
+}
+
+#[test]
+fn from_json_malformed() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let err = vm.run(vec![
+        Node::Assign("val".to_string(), Eval::FnCall("from_json".to_string(), vec![Eval::String("[1, 2".to_string())])),
+    ]).unwrap_err();
+
+    assert!(matches!(err, RuntimeError::InvalidJson(_)));
+}
+
+#[test]
+fn from_json_malformed_allocates_nothing() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    for json in [r#"[{"a":1}, oops]"#, r#"{"a":{"b":2}} x"#] {
+        assert!(matches!(from_json(&mut vm, json), Err(RuntimeError::InvalidJson(_))));
+        assert!(vm.objects.is_empty());
+    }
+}
+
+#[test]
+fn from_json_unpaired_surrogate() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(from_json(&mut vm, r#""\ud83d\ude00""#), Ok(Value::String("\u{1f600}".to_string())));
+    assert!(matches!(from_json(&mut vm, r#""\ud83d\u0041""#), Err(RuntimeError::InvalidJson(_))));
+}
+
+#[test]
+fn from_json_number_grammar() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(from_json(&mut vm, "-0"), Ok(Value::Int(0)));
+    assert_eq!(from_json(&mut vm, "10"), Ok(Value::Int(10)));
+    assert_eq!(from_json(&mut vm, "-1.5e2"), Ok(Value::Float(-150.0)));
+    assert_eq!(from_json(&mut vm, "1E-1"), Ok(Value::Float(0.1)));
+
+    for json in ["+1", "007", "-01", "1.", ".5", "-", "1e", "1e+", "1.e5", "1-2", "--1"] {
+        assert!(matches!(from_json(&mut vm, json), Err(RuntimeError::InvalidJson(_))), "{} parsed", json);
+    }
+}
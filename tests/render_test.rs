@@ -0,0 +1,44 @@
+use qahlvm::ast::*;
+use qahlvm::render::*;
+
+fn var(name: &str) -> Box<Eval> {
+    Box::new(Eval::VarRef(name.to_string()))
+}
+
+#[test]
+fn render_while_loop() {
+    let program = vec![
+        Node::Assign("i".to_string(), Eval::Int(0)),
+        Node::WhileLoop(Eval::Lt(var("i"), Box::new(Eval::Int(10))), vec![
+            Node::FnCall("print".to_string(), vec![Eval::VarRef("i".to_string())]),
+            Node::Assign("i".to_string(), Eval::Add(var("i"), Box::new(Eval::Int(1)))),
+        ]),
+    ];
+
+    assert_eq!(render(&program), "i = 0; while (i < 10) { print(i); i = i + 1; }");
+}
+
+#[test]
+fn render_expressions() {
+    let expr = Eval::Mul(
+        Box::new(Eval::Add(var("a"), Box::new(Eval::Float(1.0)))),
+        Box::new(Eval::FnCall("len".to_string(), vec![Eval::String("a \"b\"".to_string())])),
+    );
+    assert_eq!(render_eval(&expr), "(a + 1.0) * len(\"a \\\"b\\\"\")");
+
+    let program = vec![
+        Node::Conditional(vec![
+            (Eval::Not(var("done")), vec![Node::Break]),
+        ], Some(vec![
+            Node::SetMember(Eval::Int(1), "count".to_string(), Eval::Array(vec![Eval::Int(1), Eval::Null])),
+        ])),
+    ];
+    assert_eq!(render(&program), "if (!done) { break; } else { #1.count = [1, null]; }");
+}
+
+#[test]
+fn render_missing_and_empty_else() {
+    let conditional = |else_block| Node::Conditional(vec![(Eval::VarRef("a".to_string()), vec![])], else_block);
+    assert_eq!(render(&[conditional(None)]), "if (a) {}");
+    assert_eq!(render(&[conditional(Some(vec![]))]), "if (a) {} else {}");
+}
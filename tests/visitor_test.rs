@@ -0,0 +1,44 @@
+use qahlvm::ast::*;
+use qahlvm::visitor::*;
+
+#[derive(Default)]
+struct CallCounter {
+    calls: Vec<String>,
+}
+
+impl Visitor for CallCounter {
+    fn visit_node(&mut self, node: &Node) {
+        if let Node::FnCall(name, _) = node {
+            self.calls.push(name.clone());
+        }
+        walk_node(self, node);
+    }
+
+    fn visit_eval(&mut self, val: &Eval) {
+        if let Eval::FnCall(name, _) = val {
+            self.calls.push(name.clone());
+        }
+        walk_eval(self, val);
+    }
+}
+
+#[test]
+fn count_function_calls() {
+    let program = vec![
+        Node::Assign("n".to_string(), Eval::FnCall("len".to_string(), vec![Eval::String("abc".to_string())])),
+        Node::WhileLoop(Eval::Gt(Box::new(Eval::VarRef("n".to_string())), Box::new(Eval::Int(0))), vec![
+            Node::FnCall("println".to_string(), vec![
+                Eval::Add(
+                    Box::new(Eval::VarRef("n".to_string())),
+                    Box::new(Eval::FnCall("double".to_string(), vec![Eval::Int(1)]))
+                ),
+            ]),
+            Node::Assign("n".to_string(), Eval::Sub(Box::new(Eval::VarRef("n".to_string())), Box::new(Eval::Int(1)))),
+        ]),
+    ];
+
+    let mut counter = CallCounter::default();
+    walk(&mut counter, &program);
+
+    assert_eq!(counter.calls, vec!["len", "println", "double"]);
+}
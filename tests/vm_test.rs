@@ -0,0 +1,1081 @@
+use qahlvm::ast::*;
+use qahlvm::error::*;
+use qahlvm::vm::*;
+
+mod common;
+
+use common::var;
+
+fn mul(lhs: Eval, rhs: Eval) -> Eval {
+    Eval::Mul(Box::new(lhs), Box::new(rhs))
+}
+
+#[test]
+fn string_repetition() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("repeated".to_string(), mul(Eval::String("ab".to_string()), Eval::Int(3))),
+        Node::Assign("empty".to_string(), mul(Eval::String("ab".to_string()), Eval::Int(0))),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["repeated"], Value::String("ababab".to_string()));
+    assert_eq!(vm.global_variables["empty"], Value::String(String::new()));
+}
+
+#[test]
+fn array_repetition() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("zeros".to_string(), mul(Eval::Array(vec![Eval::Int(0)]), Eval::Int(3))),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["zeros"], Value::Array(vec![Value::Int(0); 3]));
+
+    let err = vm.run(vec![
+        Node::Assign("bad".to_string(), mul(Eval::Array(vec![Eval::Int(0)]), Eval::Int(-1))),
+    ]).unwrap_err();
+    assert_eq!(err, RuntimeError::NegativeCount(-1));
+}
+
+#[test]
+fn switch_multi_value_cases() {
+    let switch = |value: i32| Node::Switch(
+        Eval::Int(value),
+        vec![
+            (vec![Eval::Int(1), Eval::Int(2), Eval::Int(3)], vec![
+                Node::Assign("hit".to_string(), Eval::String("small".to_string())),
+            ]),
+        ],
+        vec![
+            Node::Assign("hit".to_string(), Eval::String("default".to_string())),
+        ],
+    );
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    for value in 1..=3 {
+        vm.run(vec![switch(value)]).unwrap();
+        assert_eq!(vm.global_variables["hit"], Value::String("small".to_string()));
+    }
+
+    vm.run(vec![switch(4)]).unwrap();
+    assert_eq!(vm.global_variables["hit"], Value::String("default".to_string()));
+}
+
+#[test]
+fn void_function_returns_unit() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("void".to_string(), vec![], vec![], false),
+        DefinedFunction::new("null".to_string(), vec![], vec![Node::Return(Eval::Null)], false),
+    ]);
+
+    vm.run(vec![
+        Node::FnCall("void".to_string(), vec![]),
+        Node::Assign("unit".to_string(), Eval::FnCall("void".to_string(), vec![])),
+        Node::Assign("null".to_string(), Eval::FnCall("null".to_string(), vec![])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["unit"], Value::Unit);
+    assert_eq!(vm.global_variables["null"], Value::Null);
+    assert_ne!(vm.global_variables["unit"], vm.global_variables["null"]);
+}
+
+#[test]
+fn destructure_assign() {
+    let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+    let array = Eval::Array(vec![Eval::Int(1), Eval::Int(2), Eval::Int(3)]);
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::DestructureAssign(names(&["a", "b", "c"]), array.clone()),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["a"], Value::Int(1));
+    assert_eq!(vm.global_variables["b"], Value::Int(2));
+    assert_eq!(vm.global_variables["c"], Value::Int(3));
+
+    let err = vm.run(vec![
+        Node::DestructureAssign(names(&["a", "b"]), array),
+    ]).unwrap_err();
+    assert_eq!(err, RuntimeError::DestructureLength { expected: 2, found: 3 });
+}
+
+#[test]
+fn iter_objects() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("a".to_string(), Eval::Int(1))]),
+        Node::CreateObject(Eval::Int(2), vec![("b".to_string(), Eval::Int(2))]),
+    ]).unwrap();
+
+    let mut objects: Vec<(usize, &Object)> = vm.iter_objects().collect();
+    objects.sort_by_key(|(id, _)| *id);
+
+    assert_eq!(objects.len(), 2);
+    assert_eq!(objects[0].0, 1);
+    assert_eq!(objects[0].1.fields["a"], Value::Int(1));
+    assert_eq!(objects[1].0, 2);
+    assert_eq!(objects[1].1.fields["b"], Value::Int(2));
+}
+
+#[test]
+fn loop_iteration_cap() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.max_loop_iterations = Some(1000);
+
+    let err = vm.run(vec![
+        Node::Loop(vec![Node::Assign("x".to_string(), Eval::Int(1))]),
+    ]).unwrap_err();
+    assert_eq!(err, RuntimeError::LoopLimit(1000));
+
+    let err = vm.run(vec![
+        Node::WhileLoop(Eval::Bool(true), vec![]),
+    ]).unwrap_err();
+    assert_eq!(err, RuntimeError::LoopLimit(1000));
+}
+
+#[test]
+fn for_loop() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("total".to_string(), Eval::Int(0)),
+        Node::For("i".to_string(), Eval::Array(vec![Eval::Int(1), Eval::Int(2), Eval::Int(3)]), vec![
+            Node::Assign("total".to_string(), Eval::Add(
+                Box::new(Eval::VarRef("total".to_string())),
+                Box::new(Eval::VarRef("i".to_string())),
+            )),
+        ]),
+    ]).unwrap();
+    assert_eq!(vm.global_variables["total"], Value::Int(6));
+
+    vm.max_loop_iterations = Some(2);
+    let err = vm.run(vec![
+        Node::For("i".to_string(), Eval::Array(vec![Eval::Int(1), Eval::Int(2), Eval::Int(3)]), vec![]),
+    ]).unwrap_err();
+    assert_eq!(err, RuntimeError::LoopLimit(2));
+}
+
+#[test]
+fn run_with_timeout() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+
+    let start = std::time::Instant::now();
+    let err = vm.run_with_timeout(vec![
+        Node::Loop(vec![Node::Assign("x".to_string(), Eval::Int(1))]),
+    ], std::time::Duration::from_millis(50)).unwrap_err();
+
+    assert_eq!(err, RuntimeError::Timeout);
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    assert!(vm.deadline.is_none());
+}
+
+#[test]
+fn register_module() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.register_module("math", vec![
+        BuiltInFunction::new("name".to_string(), 0, false, |_, _| Ok(Some(Value::String("math".to_string())))),
+    ]);
+    vm.register_module("text", vec![
+        BuiltInFunction::new("name".to_string(), 0, false, |_, _| Ok(Some(Value::String("text".to_string())))),
+    ]);
+
+    vm.run(vec![
+        Node::Assign("a".to_string(), Eval::FnCall("math.name".to_string(), vec![])),
+        Node::Assign("b".to_string(), Eval::FnCall("text.name".to_string(), vec![])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["a"], Value::String("math".to_string()));
+    assert_eq!(vm.global_variables["b"], Value::String("text".to_string()));
+    assert!(!vm.functions.contains_key("name"));
+}
+
+#[test]
+fn while_loop_over_locals() {
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("count_to".to_string(), vec!["n".to_string()], vec![
+            Node::Assign("i".to_string(), Eval::Int(0)),
+            Node::WhileLoop(Eval::Lt(var("i"), var("n")), vec![
+                Node::Assign("i".to_string(), Eval::Add(var("i"), Box::new(Eval::Int(1)))),
+            ]),
+            Node::Return(Eval::VarRef("i".to_string())),
+        ], false),
+    ]);
+
+    vm.run(vec![
+        Node::Assign("res".to_string(), Eval::FnCall("count_to".to_string(), vec![Eval::Int(5)])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["res"], Value::Int(5));
+    assert!(!vm.global_variables.contains_key("i"));
+}
+
+#[test]
+fn array_and_bool_equality() {
+    let array = |items: &[i32]| Box::new(Eval::Array(items.iter().map(|i| Eval::Int(*i)).collect()));
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("equal".to_string(), Eval::Eq(array(&[1, 2]), array(&[1, 2]))),
+        Node::Assign("unequal".to_string(), Eval::Eq(array(&[1, 2]), array(&[2, 1]))),
+        Node::Assign("not_equal".to_string(), Eval::Ne(array(&[1, 2]), array(&[1, 2, 3]))),
+        Node::Assign("bools".to_string(), Eval::Eq(Box::new(Eval::Bool(true)), Box::new(Eval::Bool(true)))),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["equal"], Value::Bool(true));
+    assert_eq!(vm.global_variables["unequal"], Value::Bool(false));
+    assert_eq!(vm.global_variables["not_equal"], Value::Bool(true));
+    assert_eq!(vm.global_variables["bools"], Value::Bool(true));
+}
+
+#[test]
+fn null_and_mixed_equality() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let null = || Box::new(Eval::Null);
+
+    assert_eq!(common::eval(&mut vm, Eval::Eq(null(), null())), Ok(Value::Bool(true)));
+    assert_eq!(common::eval(&mut vm, Eval::Ne(null(), Box::new(Eval::Int(1)))), Ok(Value::Bool(true)));
+    assert_eq!(common::eval(&mut vm, Eval::Eq(Box::new(Eval::Unit), null())), Ok(Value::Bool(false)));
+    assert_eq!(
+        common::eval(&mut vm, Eval::Eq(Box::new(Eval::Int(1)), Box::new(Eval::Float(1.0)))),
+        Ok(Value::Bool(false))
+    );
+}
+
+#[test]
+fn snapshot() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("helper".to_string(), vec![], vec![], false),
+    ]);
+    vm.run(vec![
+        Node::Assign("b".to_string(), Eval::Int(2)),
+        Node::Assign("a".to_string(), Eval::String("one".to_string())),
+    ]).unwrap();
+
+    let snapshot = vm.snapshot();
+    assert_eq!(snapshot.globals, vec![
+        ("a".to_string(), Value::String("one".to_string())),
+        ("b".to_string(), Value::Int(2)),
+    ]);
+    assert!(snapshot.functions.contains(&"helper".to_string()));
+    assert_eq!(snapshot.functions.len(), vm.functions.len());
+    assert_eq!(snapshot.object_count, 0);
+    assert_eq!(snapshot.scope_depth, 0);
+}
+
+#[test]
+fn hashable_values() {
+    let mut set = std::collections::HashSet::new();
+    for val in [
+        Value::Int(1),
+        Value::Int(1),
+        Value::Bool(true),
+        Value::Float(1.5),
+        Value::Float(1.5),
+        Value::String("a".to_string()),
+    ] {
+        set.insert(HashableValue::try_from(&val).unwrap());
+    }
+
+    assert_eq!(set.len(), 4);
+    assert!(set.contains(&HashableValue::Float(1.5f32.to_bits())));
+    assert_eq!(Value::from(HashableValue::Float(1.5f32.to_bits())), Value::Float(1.5));
+    assert_eq!(HashableValue::try_from(&Value::Array(vec![])), Err(RuntimeError::Unhashable("array")));
+    assert_eq!(HashableValue::try_from(&Value::Object(0)), Err(RuntimeError::Unhashable("object")));
+}
+
+fn nested_add(depth: usize) -> Eval {
+    let mut expr = Eval::Int(0);
+    for _ in 0..depth {
+        expr = Eval::Add(Box::new(expr), Box::new(Eval::Int(1)));
+    }
+    expr
+}
+
+#[test]
+fn expression_depth_limit() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(common::eval(&mut vm, nested_add(70)), Ok(Value::Int(70)));
+
+    vm.max_expression_depth = Some(10);
+    assert_eq!(common::eval(&mut vm, nested_add(9)), Ok(Value::Int(9)));
+    assert_eq!(common::eval(&mut vm, nested_add(10)), Err(RuntimeError::ExpressionTooDeep(10)));
+
+    // The body of a function called from a nested expression gets the full depth again.
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("eight".to_string(), vec![], vec![Node::Return(nested_add(8))], false),
+    ]);
+    let mut expr = Eval::FnCall("eight".to_string(), vec![]);
+    for _ in 0..5 {
+        expr = Eval::Add(Box::new(expr), Box::new(Eval::Int(1)));
+    }
+    assert_eq!(common::eval(&mut vm, expr), Ok(Value::Int(13)));
+}
+
+#[test]
+fn assign_default() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("set".to_string(), Eval::Int(1)),
+        Node::AssignDefault("set".to_string(), Eval::Int(2)),
+        Node::AssignDefault("unset".to_string(), Eval::Int(3)),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["set"], Value::Int(1));
+    assert_eq!(vm.global_variables["unset"], Value::Int(3));
+}
+
+#[test]
+fn implicit_loop_counter() {
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("total".to_string(), Eval::Int(0)),
+        Node::Assign("passes".to_string(), Eval::Int(0)),
+        Node::WhileLoop(Eval::Lt(var("passes"), Box::new(Eval::Int(4))), vec![
+            Node::Assign("total".to_string(), Eval::Add(var("total"), var("__iter"))),
+            Node::Assign("passes".to_string(), Eval::Add(var("passes"), Box::new(Eval::Int(1)))),
+        ]),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["total"], Value::Int(6));
+    assert!(!vm.global_variables.contains_key("__iter"));
+
+    vm.loop_counter_name = "index".to_string();
+    vm.run(vec![
+        Node::Assign("passes".to_string(), Eval::Int(0)),
+        Node::WhileLoop(Eval::Lt(var("passes"), Box::new(Eval::Int(3))), vec![
+            Node::Assign("last".to_string(), Eval::VarRef("index".to_string())),
+            Node::Assign("passes".to_string(), Eval::Add(var("passes"), Box::new(Eval::Int(1)))),
+        ]),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["last"], Value::Int(2));
+}
+
+#[test]
+fn destructure_multiple_return_values() {
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("divmod".to_string(), vec!["a".to_string(), "b".to_string()], vec![
+            Node::Return(Eval::Array(vec![Eval::Div(var("a"), var("b")), Eval::Mod(var("a"), var("b"))])),
+        ], false),
+    ]);
+
+    vm.run(vec![
+        Node::Assign("n".to_string(), Eval::Int(17)),
+        Node::DestructureAssign(
+            vec!["quotient".to_string(), "remainder".to_string()],
+            Eval::FnCall("divmod".to_string(), vec![Eval::VarRef("n".to_string()), Eval::Int(5)])
+        ),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["quotient"], Value::Int(3));
+    assert_eq!(vm.global_variables["remainder"], Value::Int(2));
+}
+
+#[test]
+fn freeze() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("inner".to_string(), common::object(2))]),
+        Node::CreateObject(Eval::Int(2), vec![("a".to_string(), Eval::Int(1))]),
+        Node::SetMember(Eval::Int(1), "a".to_string(), Eval::Int(2)),
+        Node::FnCall("freeze".to_string(), vec![common::object(1)]),
+    ]).unwrap();
+    assert_eq!(vm.objects[&1].fields["a"], Value::Int(2));
+
+    assert_eq!(
+        vm.run(vec![Node::SetMember(Eval::Int(1), "a".to_string(), Eval::Int(3))]),
+        Err(RuntimeError::FrozenObject(1))
+    );
+    assert_eq!(
+        vm.run(vec![Node::SetMember(Eval::Int(2), "a".to_string(), Eval::Int(3))]),
+        Err(RuntimeError::FrozenObject(2))
+    );
+    assert_eq!(vm.objects[&1].fields["a"], Value::Int(2));
+}
+
+#[test]
+fn step_through_program() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.load(vec![
+        Node::Assign("a".to_string(), Eval::Int(1)),
+        Node::Assign("b".to_string(), Eval::Add(Box::new(Eval::VarRef("a".to_string())), Box::new(Eval::Int(1)))),
+        Node::Assign("a".to_string(), Eval::Int(3)),
+    ]);
+
+    assert_eq!(vm.step(), Some(StepResult { index: 0, result: Ok(()) }));
+    assert_eq!(vm.global_variables["a"], Value::Int(1));
+    assert!(!vm.global_variables.contains_key("b"));
+
+    assert_eq!(vm.step(), Some(StepResult { index: 1, result: Ok(()) }));
+    assert_eq!(vm.global_variables["b"], Value::Int(2));
+
+    assert_eq!(vm.step(), Some(StepResult { index: 2, result: Ok(()) }));
+    assert_eq!(vm.global_variables["a"], Value::Int(3));
+
+    assert_eq!(vm.step(), None);
+}
+
+#[test]
+fn run_until_breakpoint() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.load(vec![
+        Node::Assign("a".to_string(), Eval::Int(1)),
+        Node::Assign("b".to_string(), Eval::Int(2)),
+        Node::Assign("c".to_string(), Eval::Int(3)),
+        Node::Assign("d".to_string(), Eval::Int(4)),
+    ]);
+    vm.breakpoints.insert(0);
+    vm.breakpoints.insert(2);
+
+    assert_eq!(vm.run_until_breakpoint(), Ok(Some(0)));
+    assert!(vm.global_variables.is_empty());
+
+    assert_eq!(vm.run_until_breakpoint(), Ok(Some(2)));
+    assert_eq!(vm.global_variables["b"], Value::Int(2));
+    assert!(!vm.global_variables.contains_key("c"));
+
+    assert_eq!(vm.run_until_breakpoint(), Ok(None));
+    assert_eq!(vm.global_variables["d"], Value::Int(4));
+}
+
+#[test]
+fn with_new_scope_restores_caller_scope() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let res = vm.with_new_scope(|vm| {
+        vm.run(vec![Node::Assign("outer".to_string(), Eval::Int(1))])?;
+        vm.with_new_scope(|vm| {
+            assert_eq!(vm.locals.len(), 1);
+            assert!(!vm.local.as_ref().unwrap().contains_key("outer"));
+            vm.run(vec![Node::Assign("inner".to_string(), Eval::Int(2))])
+        })?;
+        assert_eq!(vm.local.as_ref().unwrap()["outer"], Value::Int(1));
+        assert!(!vm.local.as_ref().unwrap().contains_key("inner"));
+        vm.run(vec![Node::Assign("res".to_string(), Eval::Bool(true))])?;
+        Err::<(), _>(RuntimeError::Timeout)
+    });
+
+    assert_eq!(res, Err(RuntimeError::Timeout));
+    assert!(vm.local.is_none());
+    assert!(vm.locals.is_empty());
+    assert!(vm.global_variables.is_empty());
+}
+
+#[test]
+fn object_survives_call_boundary() {
+    let mut vm = VirtualMachine::new(GcApproach::ReferenceCounting);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("detach".to_string(), vec!["o".to_string()], vec![
+            Node::DeleteObject(Eval::Int(2)),
+            // Running a block collects every object nothing uses any more.
+            Node::Conditional(vec![(Eval::Bool(true), vec![])], None),
+            Node::Return(Eval::VarRef("o".to_string())),
+        ], false),
+    ]);
+
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("value".to_string(), Eval::Int(42))]),
+        Node::CreateObject(Eval::Int(2), vec![("child".to_string(), common::object(1))]),
+        Node::Assign("res".to_string(), Eval::FnCall("detach".to_string(), vec![common::object(1)])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["res"], Value::Object(1));
+    assert_eq!(vm.objects[&1].fields["value"], Value::Int(42));
+    assert_eq!(vm.objects_in_use, vec![(1, 1)]);
+
+    vm.run(vec![Node::Unassign("res".to_string())]).unwrap();
+    assert!(!vm.objects.contains_key(&1));
+}
+
+#[test]
+fn discarded_call_result_keeps_use_count() {
+    let mut vm = VirtualMachine::new(GcApproach::ReferenceCounting);
+    let same = || Eval::FnCall("same".to_string(), vec![Eval::VarRef("a".to_string())]);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("same".to_string(), vec!["o".to_string()], vec![
+            Node::Return(Eval::VarRef("o".to_string())),
+        ], false),
+    ]);
+
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("value".to_string(), Eval::Int(42))]),
+        Node::Assign("a".to_string(), common::object(1)),
+    ]).unwrap();
+    assert_eq!(vm.objects_in_use, vec![(1, 1)]);
+
+    vm.run(vec![
+        Node::FnCall("same".to_string(), vec![Eval::VarRef("a".to_string())]),
+        Node::Assign("equal".to_string(), Eval::Eq(Box::new(same()), Box::new(Eval::VarRef("a".to_string())))),
+    ]).unwrap();
+    assert_eq!(vm.global_variables["equal"], Value::Bool(true));
+    assert_eq!(vm.objects_in_use, vec![(1, 1)]);
+
+    vm.run(vec![Node::Unassign("a".to_string())]).unwrap();
+    assert!(!vm.objects.contains_key(&1));
+}
+
+#[test]
+fn typed_parameters() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("repeat".to_string(), vec!["text".to_string(), "times".to_string()], vec![
+            Node::Return(mul(Eval::VarRef("text".to_string()), Eval::VarRef("times".to_string()))),
+        ], false).with_arg_types(vec![ArgType::String, ArgType::Int]),
+    ]);
+
+    vm.run(vec![
+        Node::Assign("res".to_string(), Eval::FnCall("repeat".to_string(), vec![Eval::String("ab".to_string()), Eval::Int(2)])),
+    ]).unwrap();
+    assert_eq!(vm.global_variables["res"], Value::String("abab".to_string()));
+
+    let err = vm.run(vec![
+        Node::FnCall("repeat".to_string(), vec![Eval::String("ab".to_string()), Eval::Float(2.0)]),
+    ]).unwrap_err();
+    assert_eq!(err, RuntimeError::ParameterType {
+        function: "repeat".to_string(),
+        parameter: "times".to_string(),
+        expected: "int",
+        found: "float",
+    });
+    assert_eq!(err.to_string(), "Function repeat expected int for parameter times, found float");
+}
+
+#[test]
+fn loop_expression_breaks_with_value() {
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("i".to_string(), Eval::Int(0)),
+        Node::Assign("res".to_string(), Eval::LoopExpr(vec![
+            Node::Assign("i".to_string(), Eval::Add(var("i"), Box::new(Eval::Int(1)))),
+            Node::Conditional(vec![
+                (Eval::Gt(Box::new(mul(Eval::VarRef("i".to_string()), Eval::VarRef("i".to_string()))), Box::new(Eval::Int(20))), vec![
+                    Node::BreakWith(mul(Eval::VarRef("i".to_string()), Eval::Int(10))),
+                ]),
+            ], None),
+        ])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["i"], Value::Int(5));
+    assert_eq!(vm.global_variables["res"], Value::Int(50));
+}
+
+#[test]
+fn nested_break_and_continue() {
+    let is = |name: &str, val: i32| Eval::Eq(var(name), Box::new(Eval::Int(val)));
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("total".to_string(), Eval::Int(0)),
+        Node::For("item".to_string(), Eval::Array((1..=10).map(Eval::Int).collect()), vec![
+            Node::Conditional(vec![(is("item", 2), vec![Node::Continue])], None),
+            Node::Switch(Eval::VarRef("item".to_string()), vec![
+                (vec![Eval::Int(5)], vec![Node::Break]),
+            ], vec![]),
+            Node::Assign("total".to_string(), Eval::Add(var("total"), var("item"))),
+        ]),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["total"], Value::Int(1 + 3 + 4));
+}
+
+#[test]
+fn char_arithmetic() {
+    let add = |lhs: Eval, rhs: Eval| Eval::Add(Box::new(lhs), Box::new(rhs));
+    let sub = |lhs: Eval, rhs: Eval| Eval::Sub(Box::new(lhs), Box::new(rhs));
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(common::eval(&mut vm, add(Eval::Char('a'), Eval::Int(1))), Ok(Value::Char('b')));
+    assert_eq!(common::eval(&mut vm, sub(Eval::Char('d'), Eval::Int(3))), Ok(Value::Char('a')));
+    assert_eq!(common::eval(&mut vm, sub(Eval::Char('z'), Eval::Char('a'))), Ok(Value::Int(25)));
+    assert_eq!(
+        common::eval(&mut vm, Eval::Eq(Box::new(add(Eval::Char('a'), Eval::Int(2))), Box::new(Eval::Char('c')))),
+        Ok(Value::Bool(true))
+    );
+    assert_eq!(common::eval(&mut vm, sub(Eval::Char('a'), Eval::Int(100))), Err(RuntimeError::InvalidCodePoint(-3)));
+
+    let rot13 = add(
+        Eval::FnCall("chr".to_string(), vec![Eval::Int(b'a' as i32)]),
+        Eval::Mod(
+            Box::new(add(sub(Eval::Char('x'), Eval::Char('a')), Eval::Int(13))),
+            Box::new(Eval::Int(26)),
+        ),
+    );
+    assert_eq!(common::eval(&mut vm, rot13), Ok(Value::Char('k')));
+    assert_eq!(
+        common::eval(&mut vm, Eval::FnCall("ord".to_string(), vec![Eval::Char('A')])),
+        Ok(Value::Int(65))
+    );
+}
+
+fn allocate_after_delete(mode: IdMode) -> (usize, usize) {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.object_ids.mode = mode;
+
+    let first = vm.allocate_object(Default::default());
+    vm.allocate_object(Default::default());
+    vm.run(vec![Node::DeleteObject(Eval::Int(first as i32))]).unwrap();
+    (first, vm.allocate_object(Default::default()))
+}
+
+#[test]
+fn object_id_reuse() {
+    let (deleted, reused) = allocate_after_delete(IdMode::Reuse);
+    assert_eq!(reused, deleted);
+
+    let (deleted, fresh) = allocate_after_delete(IdMode::Monotonic);
+    assert_ne!(fresh, deleted);
+    assert_eq!(fresh, 2);
+}
+
+#[test]
+fn reused_id_skips_explicitly_created_object() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.object_ids.mode = IdMode::Reuse;
+
+    let id = vm.allocate_object(Default::default());
+    vm.run(vec![
+        Node::DeleteObject(Eval::Int(id as i32)),
+        Node::CreateObject(Eval::Int(id as i32), vec![]),
+    ]).unwrap();
+
+    assert_ne!(vm.allocate_object(Default::default()), id);
+}
+
+#[test]
+fn assignment_expression() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("x".to_string(), Eval::Add(
+            Box::new(Eval::Assign("y".to_string(), Box::new(Eval::Int(5)))),
+            Box::new(Eval::Int(1)),
+        )),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["y"], Value::Int(5));
+    assert_eq!(vm.global_variables["x"], Value::Int(6));
+}
+
+#[test]
+fn object_arguments_are_passed_by_reference() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("bump".to_string(), vec!["o".to_string()], vec![
+            Node::SetMember(Eval::String("o".to_string()), "value".to_string(), Eval::Int(2)),
+            Node::SetMember(
+                Eval::GetMember(Box::new(Eval::String("o".to_string())), "child".to_string()),
+                "value".to_string(),
+                Eval::Int(2),
+            ),
+        ], false),
+    ]);
+
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("value".to_string(), Eval::Int(1))]),
+        Node::CreateObject(Eval::Int(2), vec![
+            ("value".to_string(), Eval::Int(1)),
+            ("child".to_string(), common::object(1)),
+        ]),
+        Node::FnCall("bump".to_string(), vec![
+            Eval::FnCall("pass_by_value".to_string(), vec![common::object(2)]),
+        ]),
+    ]).unwrap();
+
+    assert_eq!(vm.objects[&2].fields["value"], Value::Int(1));
+    assert_eq!(vm.objects[&1].fields["value"], Value::Int(1));
+
+    vm.run(vec![Node::FnCall("bump".to_string(), vec![common::object(2)])]).unwrap();
+
+    assert_eq!(vm.objects[&2].fields["value"], Value::Int(2));
+    assert_eq!(vm.objects[&1].fields["value"], Value::Int(2));
+}
+
+#[test]
+fn panic_message_includes_scope() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("broken".to_string(), vec!["counter".to_string()], vec![
+            Node::Unassign("missing".to_string()),
+        ], false),
+    ]);
+
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        vm.run(vec![Node::FnCall("broken".to_string(), vec![Eval::Int(1)])])
+    }));
+
+    let payload = res.unwrap_err();
+    let msg = payload.downcast_ref::<String>().unwrap();
+    assert!(msg.starts_with("Variable missing does not exist"));
+    assert!(msg.contains("Unassign"));
+    assert!(msg.contains("counter"));
+}
+
+#[test]
+fn expression_panics_include_scope() {
+    let panic_message = |node: Node| {
+        let mut vm = VirtualMachine::new(GcApproach::None);
+        vm.global_variables.insert("count".to_string(), Value::Int(1));
+        vm.run(vec![Node::CreateObject(Eval::Int(1), vec![])]).unwrap();
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.run(vec![node])));
+        res.unwrap_err().downcast_ref::<String>().unwrap().clone()
+    };
+
+    let msg = panic_message(Node::Assign("x".to_string(), Eval::VarRef("missing".to_string())));
+    assert!(msg.starts_with("Variable missing does not exist"));
+    assert!(msg.contains("Assign") && msg.contains("count"));
+
+    let msg = panic_message(Node::Assign("x".to_string(), Eval::Lt(Box::new(Eval::Int(1)), Box::new(Eval::Bool(true)))));
+    assert!(msg.starts_with("Cannot apply < to Int(1) and Bool(true)"));
+    assert!(msg.contains("count"));
+
+    let msg = panic_message(Node::Assign("x".to_string(), Eval::GetMember(Box::new(common::object(1)), "name".to_string())));
+    assert!(msg.starts_with("Object 1 has no field name"));
+
+    let msg = panic_message(Node::DeleteObject(Eval::Bool(true)));
+    assert!(msg.starts_with("Object id must be an int, found bool"));
+    assert!(msg.contains("DeleteObject"));
+}
+
+#[test]
+fn run_main() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.load(vec![
+        Node::FnDef("double".to_string(), vec!["n".to_string()], vec![
+            Node::Return(Eval::Mul(var("n"), Box::new(Eval::Int(2)))),
+        ]),
+        Node::FnDef("main".to_string(), vec!["x".to_string()], vec![
+            Node::Return(Eval::Add(
+                Box::new(Eval::FnCall("double".to_string(), vec![Eval::VarRef("x".to_string())])),
+                Box::new(Eval::Int(1)),
+            )),
+        ]),
+    ]);
+
+    assert_eq!(vm.run_main(vec![Value::Int(20)]), Ok(Value::Int(41)));
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(vm.run_main(vec![]), Err(RuntimeError::NoProgram));
+    vm.load(vec![Node::FnDef("helper".to_string(), vec![], vec![])]);
+    assert_eq!(vm.run_main(vec![]), Err(RuntimeError::MissingMain));
+}
+
+#[test]
+fn run_main_skips_top_level_statements() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.load(vec![
+        Node::Assign("setup".to_string(), Eval::Int(1)),
+        Node::FnDef("main".to_string(), vec![], vec![
+            Node::Return(Eval::Int(7)),
+        ]),
+        Node::FnCall("main".to_string(), vec![]),
+    ]);
+
+    assert_eq!(vm.run_main(vec![]), Ok(Value::Int(7)));
+    assert!(!vm.global_variables.contains_key("setup"));
+}
+
+#[test]
+fn cast() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let cast = |val: Eval, to: TypeName| Eval::Cast(Box::new(val), to);
+
+    assert!(cast(Eval::Int(1), TypeName::Int).is_an_operator());
+    assert_eq!(common::eval(&mut vm, cast(Eval::Float(-2.75), TypeName::Int)), Ok(Value::Int(-2)));
+    assert_eq!(common::eval(&mut vm, cast(common::string(" 1.5 "), TypeName::Float)), Ok(Value::Float(1.5)));
+    assert_eq!(common::eval(&mut vm, cast(Eval::Int(0), TypeName::Bool)), Ok(Value::Bool(false)));
+    assert_eq!(common::eval(&mut vm, cast(Eval::Float(3.0), TypeName::String)), Ok(Value::String("3.0".to_string())));
+    assert_eq!(
+        common::eval(&mut vm, cast(common::string("abc"), TypeName::Float)),
+        Err(RuntimeError::InvalidCast { from: "string", to: "float" })
+    );
+    assert_eq!(
+        common::eval(&mut vm, cast(Eval::Null, TypeName::Int)),
+        Err(RuntimeError::InvalidCast { from: "null", to: "int" })
+    );
+}
+
+#[test]
+fn load_functions_from_library() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    let library = vec![
+        Node::Assign("side_effect".to_string(), Eval::Int(1)),
+        Node::FnDef("square".to_string(), vec!["n".to_string()], vec![
+            Node::Return(mul(Eval::VarRef("n".to_string()), Eval::VarRef("n".to_string()))),
+        ]),
+    ];
+    vm.load_functions_from(&library);
+
+    assert!(!vm.global_variables.contains_key("side_effect"));
+    assert_eq!(common::eval(&mut vm, common::call("square", vec![Eval::Int(7)])), Ok(Value::Int(49)));
+}
+
+fn branch_chain(x: i32, else_block: Option<Vec<Node>>) -> Node {
+    let x_is = |n: i32| Eval::Eq(Box::new(Eval::Int(x)), Box::new(Eval::Int(n)));
+    let set = |branch: &str| vec![Node::Assign("branch".to_string(), common::string(branch))];
+    Node::Conditional(vec![
+        (x_is(1), set("first")),
+        (Eval::Gt(Box::new(Eval::Int(x)), Box::new(Eval::Int(1))), set("second")),
+        // Also true for 2, but only the first true condition runs.
+        (x_is(2), set("third")),
+    ], else_block)
+}
+
+#[test]
+fn else_if_chain() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![branch_chain(2, None)]).unwrap();
+    assert_eq!(vm.global_variables["branch"], Value::String("second".to_string()));
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![branch_chain(0, Some(vec![
+        Node::Assign("branch".to_string(), common::string("else")),
+    ]))]).unwrap();
+    assert_eq!(vm.global_variables["branch"], Value::String("else".to_string()));
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![branch_chain(0, None)]).unwrap();
+    assert!(!vm.global_variables.contains_key("branch"));
+}
+
+#[test]
+fn repeat_until() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("x".to_string(), Eval::Float(100.0)),
+        Node::Assign("steps".to_string(), Eval::Int(0)),
+        Node::RepeatUntil(vec![
+            Node::Assign("x".to_string(), Eval::Div(var("x"), Box::new(Eval::Float(2.0)))),
+            Node::Assign("steps".to_string(), Eval::Add(var("steps"), Box::new(Eval::Int(1)))),
+        ], Eval::Lt(var("x"), Box::new(Eval::Float(1.0)))),
+        // The body runs once even though the condition already holds.
+        Node::RepeatUntil(vec![
+            Node::Assign("ran".to_string(), Eval::Bool(true)),
+        ], Eval::Bool(true)),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["x"], Value::Float(0.78125));
+    assert_eq!(vm.global_variables["steps"], Value::Int(7));
+    assert_eq!(vm.global_variables["ran"], Value::Bool(true));
+}
+
+#[test]
+fn closure_captures_local_counter() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("make_counter".to_string(), vec!["step".to_string()], vec![
+            Node::Assign("count".to_string(), Eval::Int(0)),
+            Node::Return(Eval::Closure(vec![], vec![
+                Node::Assign("count".to_string(), Eval::Add(var("count"), var("step"))),
+                Node::Return(Eval::VarRef("count".to_string())),
+            ])),
+        ], false),
+    ]);
+
+    vm.run(vec![
+        Node::Assign("by_one".to_string(), common::call("make_counter", vec![Eval::Int(1)])),
+        Node::Assign("by_ten".to_string(), common::call("make_counter", vec![Eval::Int(10)])),
+        Node::FnCall("by_one".to_string(), vec![]),
+        Node::FnCall("by_ten".to_string(), vec![]),
+        Node::Assign("one".to_string(), common::call("by_one", vec![])),
+        Node::Assign("ten".to_string(), common::call("by_ten", vec![])),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["one"], Value::Int(2));
+    assert_eq!(vm.global_variables["ten"], Value::Int(20));
+    assert!(!vm.global_variables.contains_key("count"));
+}
+
+#[test]
+fn unused_closures_are_collected() {
+    let closures = |vm: &VirtualMachine| vm.functions.keys().filter(|name| name.starts_with("<closure")).count();
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("make_adder".to_string(), vec!["n".to_string()], vec![
+            Node::Return(Eval::Closure(vec!["x".to_string()], vec![
+                Node::Return(Eval::Add(var("n"), var("x"))),
+            ])),
+        ], false),
+        DefinedFunction::new("spin".to_string(), vec![], vec![
+            Node::Times(Eval::Int(200), vec![
+                Node::FnCall("make_adder".to_string(), vec![Eval::Int(0)]),
+            ]),
+            Node::Return(Eval::Int(1)),
+        ], false),
+    ]);
+
+    vm.run(vec![
+        Node::Assign("keep".to_string(), common::call("make_adder", vec![Eval::Int(100)])),
+        Node::Times(Eval::Int(500), vec![
+            Node::Assign("tmp".to_string(), common::call("make_adder", vec![Eval::VarRef("__iter".to_string())])),
+            Node::Assign("last".to_string(), common::call("tmp", vec![Eval::Int(1)])),
+        ]),
+    ]).unwrap();
+    assert_eq!(vm.global_variables["last"], Value::Int(500));
+    assert!(closures(&vm) <= 128, "{} closures left", closures(&vm));
+
+    // Closures only held by a `For` array or by a caller mid-expression survive a collection.
+    let adders = (0..100).map(|i| common::call("make_adder", vec![Eval::Int(i)])).collect();
+    vm.run(vec![
+        Node::Assign("total".to_string(), Eval::Int(0)),
+        Node::For("f".to_string(), Eval::Array(adders), vec![
+            Node::Assign("total".to_string(), Eval::Add(var("total"), Box::new(common::call("f", vec![Eval::Int(0)])))),
+        ]),
+        Node::Assign("applied".to_string(), common::call("apply", vec![
+            common::call("make_adder", vec![Eval::Int(5)]),
+            Eval::Array(vec![common::call("spin", vec![])]),
+        ])),
+    ]).unwrap();
+    assert_eq!(vm.global_variables["total"], Value::Int((0..100).sum()));
+    assert_eq!(vm.global_variables["applied"], Value::Int(6));
+
+    vm.run(vec![
+        Node::Times(Eval::Int(300), vec![
+            Node::Assign("tmp".to_string(), common::call("make_adder", vec![Eval::Int(0)])),
+        ]),
+    ]).unwrap();
+    assert!(closures(&vm) <= 128, "{} closures left", closures(&vm));
+    assert_eq!(common::eval(&mut vm, common::call("keep", vec![Eval::Int(1)])), Ok(Value::Int(101)));
+}
+
+#[test]
+fn strict_members() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::CreateObject(Eval::Int(1), vec![("name".to_string(), common::string("a"))]),
+        Node::SetMember(Eval::Int(1), "nmae".to_string(), common::string("b")),
+    ]).unwrap();
+    assert_eq!(vm.objects[&1].fields["nmae"], Value::String("b".to_string()));
+
+    vm.strict_members = true;
+    vm.run(vec![Node::SetMember(Eval::Int(1), "name".to_string(), common::string("c"))]).unwrap();
+    assert_eq!(vm.objects[&1].fields["name"], Value::String("c".to_string()));
+    assert_eq!(
+        vm.run(vec![Node::SetMember(Eval::Int(1), "age".to_string(), Eval::Int(3))]),
+        Err(RuntimeError::UnknownField { object: 1, field: "age".to_string() })
+    );
+    assert!(!vm.objects[&1].fields.contains_key("age"));
+}
+
+#[test]
+fn for_range() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("odd".to_string(), Eval::Int(0)),
+        Node::ForRange("i".to_string(), Eval::Int(0), Eval::Int(1_000_000), Eval::Int(1), vec![
+            Node::Assign("odd".to_string(), Eval::Add(var("odd"), Box::new(Eval::Mod(var("i"), Box::new(Eval::Int(2)))))),
+        ]),
+        Node::Assign("down".to_string(), Eval::Int(0)),
+        Node::ForRange("j".to_string(), Eval::Int(10), Eval::Int(0), Eval::Int(-3), vec![
+            Node::Assign("down".to_string(), Eval::Add(var("down"), var("j"))),
+        ]),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["odd"], Value::Int(500_000));
+    assert_eq!(vm.global_variables["i"], Value::Int(999_999));
+    // 10 + 7 + 4 + 1
+    assert_eq!(vm.global_variables["down"], Value::Int(22));
+
+    assert_eq!(
+        vm.run(vec![Node::ForRange("k".to_string(), Eval::Int(0), Eval::Int(1), Eval::Int(0), vec![])]),
+        Err(RuntimeError::ZeroStep)
+    );
+}
+
+#[test]
+fn execution_log() {
+    let program = || vec![
+        Node::Assign("i".to_string(), Eval::Int(0)),
+        Node::WhileLoop(Eval::Lt(var("i"), Box::new(Eval::Int(3))), vec![
+            Node::Assign("i".to_string(), Eval::Add(var("i"), Box::new(Eval::Int(1)))),
+        ]),
+        Node::FnCall("len".to_string(), vec![Eval::Array(vec![])]),
+    ];
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(program()).unwrap();
+    assert!(vm.execution_log().is_empty());
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.enable_execution_log(100);
+    vm.run(program()).unwrap();
+    assert_eq!(vm.execution_log(), vec!["Assign", "WhileLoop", "Assign", "Assign", "Assign", "FnCall"]);
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.enable_execution_log(2);
+    vm.run(program()).unwrap();
+    assert_eq!(vm.execution_log(), vec!["Assign", "FnCall"]);
+}
+
+#[test]
+fn times() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.run(vec![
+        Node::Assign("count".to_string(), Eval::Int(0)),
+        Node::Assign("last".to_string(), Eval::Int(-1)),
+        Node::Times(Eval::Add(Box::new(Eval::Int(2)), Box::new(Eval::Int(3))), vec![
+            Node::Assign("count".to_string(), Eval::Add(var("count"), Box::new(Eval::Int(1)))),
+            Node::Assign("last".to_string(), Eval::VarRef(DEFAULT_LOOP_COUNTER_NAME.to_string())),
+        ]),
+        Node::Times(Eval::Int(0), vec![
+            Node::Assign("count".to_string(), Eval::Int(100)),
+        ]),
+    ]).unwrap();
+
+    assert_eq!(vm.global_variables["count"], Value::Int(5));
+    assert_eq!(vm.global_variables["last"], Value::Int(4));
+    assert_eq!(vm.run(vec![Node::Times(Eval::Int(-1), vec![])]), Err(RuntimeError::NegativeCount(-1)));
+    assert_eq!(vm.run(vec![Node::Times(Eval::Float(1.0), vec![])]), Err(RuntimeError::CountType("float")));
+}
+
+#[test]
+fn guard_returns_early() {
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.add_defined_functions(vec![
+        DefinedFunction::new("halve".to_string(), vec!["n".to_string()], vec![
+            Node::Guard(Eval::Gt(var("n"), Box::new(Eval::Int(0))), vec![
+                Node::Return(Eval::String("invalid".to_string())),
+            ]),
+            Node::For("i".to_string(), Eval::Array(vec![Eval::Int(1), Eval::Int(2)]), vec![
+                Node::Guard(Eval::Ne(var("n"), Box::new(Eval::Int(1))), vec![
+                    Node::Return(Eval::String("odd".to_string())),
+                ]),
+            ]),
+            Node::Return(Eval::Div(var("n"), Box::new(Eval::Int(2)))),
+        ], false),
+    ]);
+
+    assert_eq!(vm.call_with_values("halve", vec![Value::Int(-3)]), Ok(Value::String("invalid".to_string())));
+    assert_eq!(vm.call_with_values("halve", vec![Value::Int(1)]), Ok(Value::String("odd".to_string())));
+    assert_eq!(vm.call_with_values("halve", vec![Value::Int(8)]), Ok(Value::Int(4)));
+}
+
+#[test]
+fn collect_assertions() {
+    let assert = |name: &str, args: Vec<Eval>| Node::FnCall(name.to_string(), args);
+    let script = vec![
+        assert("assert", vec![Eval::Bool(true)]),
+        assert("assert", vec![Eval::Bool(false), Eval::String("flag unset".to_string())]),
+        assert("assert_eq", vec![Eval::Int(2), Eval::Add(Box::new(Eval::Int(1)), Box::new(Eval::Int(1)))]),
+        assert("assert_eq", vec![Eval::Int(3), Eval::Int(4)]),
+        assert("assert_eq", vec![Eval::Array(vec![Eval::Int(1)]), Eval::Array(vec![Eval::Int(1)])]),
+    ];
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    vm.collect_assertions = true;
+    vm.run(script.clone()).unwrap();
+    assert_eq!(vm.assertion_results(), &AssertionResults {
+        passed: 3,
+        failed: 2,
+        failures: vec!["flag unset".to_string(), "3 != 4".to_string()],
+    });
+
+    let mut vm = VirtualMachine::new(GcApproach::None);
+    assert_eq!(vm.run(script), Err(RuntimeError::AssertionFailed("flag unset".to_string())));
+    assert_eq!(vm.assertion_results().passed, 1);
+}